@@ -0,0 +1,49 @@
+// balance reads come from account_balance, so they should take the same time whether the account has a handful of
+// transactions or a hundred thousand. history is inserted straight into the database to keep the setup fast
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+
+const HISTORY_SIZES: [i32; 3] = [10, 10_000, 100_000];
+
+async fn grow_history(merchant: &Merchant, size: i32) {
+    let db = db().await;
+    db.batch_execute(&format!("\
+     with trans as (insert into transaction (order_id, type, status, merch_id, request_hash, created) \
+      select 'bench-' || {merch_id} || '-' || n, 'fund', 'completed', {merch_id}, '', now() \
+      from generate_series(1, {size}) n returning id) \
+     insert into transaction_item (amount, created, trans_id, src_acc_id, dest_acc_id, type) \
+      select 1, now(), id, 1, {account_id}, 'principal' from trans; \
+     insert into account_balance (acc_id, balance, pending_debits, pending_credits, updated) \
+      values ({account_id}, {size}, 0, 0, now());",
+                              merch_id = merchant.id, account_id = merchant.account_id, size = size)).await.unwrap();
+}
+
+fn balance(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let server = Server::start();
+    let mut group = c.benchmark_group("account balance");
+    for size in HISTORY_SIZES {
+        let (api, account_id) = runtime.block_on(async {
+            let merchant = create_merchant(&format!("Balance bench {}", size)).await;
+            grow_history(&merchant, size).await;
+            (server.login(&merchant).await, merchant.account_id)
+        });
+        let path = format!("/api/account/{}/balance", account_id);
+        group.bench_with_input(BenchmarkId::new("transactions", size), &path, |b, path| {
+            b.to_async(&runtime).iter(|| async {
+                let (status, balance) = api.get(path).await;
+                assert_eq!(status, 200);
+                assert_eq!(balance["posted"], size);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, balance);
+criterion_main!(benches);
@@ -1,4 +1,6 @@
 INSERT INTO account (active, currency, name, merch_id) VALUES (true, 'USD', 'Cash account', 1);
 INSERT INTO account (active, currency, name, merch_id) VALUES (true, 'USD', 'Card account', 1);
 INSERT INTO account (active, currency, name, merch_id) VALUES (true, 'USD', 'Fee account', 1);
-INSERT INTO account (active, currency, name, merch_id) VALUES (true, 'USD', 'Wayne USD account', 2);
\ No newline at end of file
+INSERT INTO account (active, currency, name, merch_id) VALUES (true, 'USD', 'Wayne USD account', 2);
+INSERT INTO card_program (name, bin_from, bin_to, pan_length, expiry_months, merch_id) VALUES ('Acme virtual', 42424200, 42424299, 16, 36, 1);
+INSERT INTO card_program (name, bin_from, bin_to, pan_length, expiry_months, merch_id) VALUES ('Wayne virtual', 53999900, 53999999, 16, 36, 2);
\ No newline at end of file
@@ -34,31 +34,90 @@ create table customer
     state_region varchar,
     country      varchar not null,
     postal_code  varchar not null,
+    kyc_status   varchar not null,
+    kyc_reason   varchar,
     merch_id     integer
         constraint cust_merch_fkey references merchant (id)
 );
 
+create table card_program
+(
+    id            serial
+        constraint card_program_pkey primary key,
+    name          varchar not null,
+    bin_from      bigint  not null,
+    bin_to        bigint  not null,
+    pan_length    integer not null,
+    expiry_months integer not null,
+    merch_id      integer
+        constraint card_program_merch_fkey references merchant (id),
+    constraint card_program_bin_range_check check (0 < bin_from and bin_from <= bin_to
+        and length(bin_from::text) = length(bin_to::text)),
+    constraint card_program_pan_length_check check (pan_length between 12 and 19)
+);
+
+create table card_vault
+(
+    token       varchar                  not null
+        constraint card_vault_pkey primary key,
+    key_version integer                  not null,
+    wrapped_key varchar                  not null,
+    pan         varchar                  not null,
+    cvv         varchar                  not null,
+    created     timestamp with time zone not null,
+    revealed    timestamp with time zone
+);
+
 create table card
 (
-    id      serial
+    id              serial
         constraint card_pkey primary key,
-    type    varchar                  not null,
-    created timestamp with time zone not null,
-    cust_id integer
+    type            varchar                  not null,
+    status          varchar                  not null,
+    masked_pan      varchar                  not null,
+    pan_token       varchar                  not null
+        constraint card_pan_token_fkey references card_vault (token),
+    pan_fingerprint varchar                  not null
+        constraint card_pan_fingerprint_key unique,
+    exp_month       integer                  not null,
+    exp_year        integer                  not null,
+    created         timestamp with time zone not null,
+    cust_id         integer
         constraint card_cust_fkey references customer (id),
-    acc_id  integer
-        constraint card_acc_fkey references account (id)
+    acc_id          integer
+        constraint card_acc_fkey references account (id),
+    program_id      integer
+        constraint card_program_fkey references card_program (id)
 );
 
 create table transaction
 (
-    id       serial
+    id                  serial
         constraint transaction_pkey primary key,
-    order_id varchar not null,
-    type     varchar not null,
-    status   varchar not null
+    order_id            varchar,
+    system_ref          varchar,
+    type                varchar not null,
+    status              varchar not null,
+    merch_id            integer
+        constraint trans_merch_fkey references merchant (id),
+    request_hash        varchar not null,
+    created             timestamp with time zone not null,
+    expires             timestamp with time zone,
+    parent_id           integer
+        constraint trans_parent_fkey references transaction (id),
+    card_id             integer
+        constraint trans_card_fkey references card (id),
+    merchant_descriptor varchar,
+    mcc                 varchar,
+    merchant_country    varchar,
+    auth_code           varchar,
+    constraint trans_order_or_system_ref_check check ((order_id is null) <> (system_ref is null))
 );
 
+-- a failed spend gives its order up, so the same orderId can be tried again
+create unique index trans_merch_order_key on transaction (merch_id, order_id) where status <> 'failed';
+create unique index trans_merch_system_ref_key on transaction (merch_id, system_ref) where status <> 'failed';
+
 create table transaction_item
 (
     id          serial
@@ -72,7 +131,8 @@ create table transaction_item
     dest_acc_id integer
         constraint trans_itm_dest_acc_fkey references account (id),
     card_id     integer
-        constraint trans_card_fkey references card (id)
+        constraint trans_card_fkey references card (id),
+    type        varchar                  not null
 );
 
 create table transaction_fee
@@ -83,4 +143,171 @@ create table transaction_fee
     type   varchar not null,
     acc_id integer
         constraint trans_fee_acc_fkey references account (id)
-);
\ No newline at end of file
+);
+
+create table refresh_token
+(
+    id         serial
+        constraint refresh_token_pkey primary key,
+    token_hash varchar                  not null
+        constraint refresh_token_hash_key unique,
+    merch_id   integer
+        constraint refresh_token_merch_fkey references merchant (id),
+    created    timestamp with time zone not null,
+    expires    timestamp with time zone not null,
+    revoked    boolean                  not null
+);
+
+create table idempotency_key
+(
+    id           serial
+        constraint idempotency_key_pkey primary key,
+    merch_id     integer
+        constraint idempotency_key_merch_fkey references merchant (id),
+    scope        varchar                  not null,
+    key          varchar                  not null,
+    request_hash varchar                  not null,
+    resource_id  integer                  not null,
+    created      timestamp with time zone not null,
+    constraint idempotency_key_merch_scope_key unique (merch_id, scope, key)
+);
+
+create table account_balance
+(
+    acc_id          integer
+        constraint account_balance_pkey primary key
+        constraint account_balance_acc_fkey references account (id),
+    balance         bigint                   not null,
+    pending_debits  bigint                   not null,
+    pending_credits bigint                   not null,
+    updated         timestamp with time zone not null
+);
+
+create table transaction_status_history
+(
+    id       serial
+        constraint transaction_status_history_pkey primary key,
+    trans_id integer                  not null
+        constraint trans_status_trans_fkey references transaction (id),
+    status   varchar                  not null,
+    created  timestamp with time zone not null
+);
+
+create table card_reveal_audit
+(
+    id       serial
+        constraint card_reveal_audit_pkey primary key,
+    card_id  integer                  not null,
+    merch_id integer                  not null,
+    revealed boolean                  not null,
+    refusal  varchar,
+    created  timestamp with time zone not null
+);
+
+create table card_limit
+(
+    card_id         integer                  not null
+        constraint card_limit_pkey primary key
+        constraint card_limit_card_fkey references card (id),
+    per_transaction bigint,
+    daily           bigint,
+    weekly          bigint,
+    monthly         bigint,
+    lifetime        bigint,
+    daily_count     bigint,
+    weekly_count    bigint,
+    monthly_count   bigint,
+    allowed_from    time,
+    allowed_to      time,
+    updated         timestamp with time zone not null
+);
+
+create table card_restriction
+(
+    card_id           integer                  not null
+        constraint card_restriction_pkey primary key
+        constraint card_restriction_card_fkey references card (id),
+    allowed_mccs      varchar[]                not null,
+    blocked_mccs      varchar[]                not null,
+    allowed_countries varchar[]                not null,
+    blocked_countries varchar[]                not null,
+    updated           timestamp with time zone not null
+);
+
+create table authorization_webhook
+(
+    merch_id         integer                  not null
+        constraint authorization_webhook_pkey primary key
+        constraint authorization_webhook_merch_fkey references merchant (id),
+    url              varchar                  not null,
+    timeout_ms       integer                  not null,
+    default_decision varchar                  not null,
+    funding_acc_id   integer
+        constraint authorization_webhook_acc_fkey references account (id),
+    secret           varchar                  not null,
+    updated          timestamp with time zone not null
+);
+
+create table webhook_endpoint
+(
+    id       serial
+        constraint webhook_endpoint_pkey primary key,
+    merch_id integer                  not null
+        constraint webhook_endpoint_merch_fkey references merchant (id),
+    url      varchar                  not null,
+    secret   varchar                  not null,
+    active   boolean                  not null,
+    created  timestamp with time zone not null
+);
+
+create table webhook_event
+(
+    id       serial
+        constraint webhook_event_pkey primary key,
+    merch_id integer                  not null
+        constraint webhook_event_merch_fkey references merchant (id),
+    type     varchar                  not null,
+    payload  varchar                  not null,
+    created  timestamp with time zone not null
+);
+
+create table webhook_delivery
+(
+    id               serial
+        constraint webhook_delivery_pkey primary key,
+    event_id         integer                  not null
+        constraint webhook_delivery_event_fkey references webhook_event (id),
+    endpoint_id      integer                  not null
+        constraint webhook_delivery_endpoint_fkey references webhook_endpoint (id),
+    status           varchar                  not null,
+    attempts         integer                  not null,
+    next_attempt     timestamp with time zone,
+    last_status_code integer,
+    last_error       varchar,
+    delivered        timestamp with time zone,
+    created          timestamp with time zone not null,
+    constraint webhook_delivery_event_endpoint_key unique (event_id, endpoint_id)
+);
+
+create table kyc_document
+(
+    id           serial
+        constraint kyc_document_pkey primary key,
+    cust_id      integer                  not null
+        constraint kyc_document_cust_fkey references customer (id),
+    type         varchar                  not null,
+    file_name    varchar                  not null,
+    content_type varchar                  not null,
+    size         bigint                   not null,
+    sha256       varchar,
+    created      timestamp with time zone not null
+);
+
+create table kyc_policy
+(
+    merch_id             integer                  not null
+        constraint kyc_policy_pkey primary key
+        constraint kyc_policy_merch_fkey references merchant (id),
+    require_verification boolean                  not null,
+    updated              timestamp with time zone not null
+);
@@ -1,9 +1,17 @@
-use crate::db::DBConn;
-use crate::Errors;
-use crate::Errors::AccountError;
+use tokio_postgres::{GenericClient, Transaction};
+use chrono::prelude::*;
+use serde::Serialize;
+use warp::reply::Response;
+use warp::Rejection;
+use crate::db::{DBPool, get_db_conn};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{AccountNotFound, InternalError};
+use crate::token::AuthMerchant;
+use crate::transaction;
 
 pub const CASH_ACCOUNT_ID: i32 = 1;
 
+#[allow(dead_code)]
 pub struct Account {
     pub id: i32,
     pub name: String,
@@ -12,13 +20,64 @@ pub struct Account {
     pub merch_id: i32,
 }
 
-pub async fn get_active_by_id(conn: &DBConn, id: i32) -> Result<Account, Errors> {
+#[derive(Serialize)]
+pub struct BalanceResponse {
+    pub account_id: i32,
+    pub available: i64,
+    pub posted: i64,
+    pub pending_debits: i64,
+    pub pending_credits: i64,
+    pub currency: String,
+    pub as_of: DateTime<Utc>,
+}
+
+pub async fn balance_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_balance(&*conn, id, merchant.id).await)
+}
+
+pub async fn get_balance<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<BalanceResponse, Errors> {
+    let account = get_active_by_id_and_merchant(conn, id, merch_id).await?;
+    let as_of = Utc::now();
+    let balance = transaction::get_balance(conn, account.id).await?;
+    Ok(BalanceResponse {
+        account_id: account.id,
+        available: balance.available(),
+        posted: balance.posted,
+        pending_debits: balance.pending_debits,
+        pending_credits: balance.pending_credits,
+        currency: account.currency,
+        as_of,
+    })
+}
+
+pub async fn get_active_by_id<C: GenericClient>(conn: &C, id: i32) -> Result<Account, Errors> {
     match conn.query("select * from account where id=$1 and active = true", &[&id]).await
         .map_err(|e| {
-            AccountError(e.to_string())
-        })?.get(0) {
+            InternalError(e.to_string())
+        })?.first() {
+        None => {
+            Err(AccountNotFound)
+        }
+        Some(row) => {
+            Ok(Account {
+                id,
+                name: row.get("name"),
+                active: true,
+                currency: row.get("currency"),
+                merch_id: row.get("merch_id"),
+            })
+        }
+    }
+}
+
+pub async fn get_active_by_id_and_merchant<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Account, Errors> {
+    match conn.query("select * from account where id=$1 and merch_id=$2 and active = true", &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
         None => {
-            Err(AccountError("account does not exist".to_string()))
+            Err(AccountNotFound)
         }
         Some(row) => {
             Ok(Account {
@@ -30,4 +89,12 @@ pub async fn get_active_by_id(conn: &DBConn, id: i32) -> Result<Account, Errors>
             })
         }
     }
-}
\ No newline at end of file
+}
+
+pub async fn lock_by_id(conn: &Transaction<'_>, id: i32) -> Result<(), Errors> {
+    conn.execute("select id from account where id=$1 for update", &[&id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    Ok(())
+}
@@ -1,71 +1,188 @@
 use crate::db::{DBPool, DBConn, get_db_conn};
-use crate::token::validate_auth_header;
+use crate::token::AuthMerchant;
 use serde::{Serialize, Deserialize};
-use crate::Errors::{CardError, TransactionError};
-use crate::{Errors, ErrorResponse};
-use warp::reply::{Json, json};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{CardAlreadyRevealed, CardNotActive, CardNotFound, CardProgramNotFound, CustomerNotActive,
+                           InternalError, InvalidAmount, InvalidStatusTransition};
+use warp::reply::Response;
 use warp::Rejection;
-use crate::transaction;
+use tokio_postgres::{GenericClient, Row, Transaction};
+use crate::{account, customer, decision, idempotency, kyc, limit, restriction, transaction, vault, webhook};
+use crate::pan::CardNumber;
+use crate::transaction::{Order, TransactionType};
 use chrono::prelude::*;
-use crate::transaction::TransactionType::{VirtualCardDeposit, VirtualCardWithdraw};
+use crate::transaction::TransactionType::{CardAuthorization, VirtualCardDeposit, VirtualCardWithdraw};
 
-const CARD_ACCOUNT_ID: i32 = 2;
-const FEE_ACCOUNT_ID: i32 = 3;
+pub const CARD_ACCOUNT_ID: i32 = 2;
+pub const FEE_ACCOUNT_ID: i32 = 3;
+const IDEMPOTENCY_SCOPE: &str = "card";
+const PAN_ATTEMPTS: usize = 10;
 
+#[derive(PartialEq)]
+pub enum CardStatus {
+    Active,
+    Frozen,
+    Terminated,
+}
+
+impl CardStatus {
+    fn to_db_val(&self) -> &'static str {
+        match self {
+            CardStatus::Active => { "active" }
+            CardStatus::Frozen => { "frozen" }
+            CardStatus::Terminated => { "terminated" }
+        }
+    }
+
+    fn from_db_val(val: &str) -> Option<CardStatus> {
+        match val {
+            "active" => { Some(CardStatus::Active) }
+            "frozen" => { Some(CardStatus::Frozen) }
+            "terminated" => { Some(CardStatus::Terminated) }
+            _ => { None }
+        }
+    }
+
+    fn can_transition_to(&self, next: &CardStatus) -> bool {
+        match self {
+            CardStatus::Active => { matches!(next, CardStatus::Frozen | CardStatus::Terminated) }
+            CardStatus::Frozen => { matches!(next, CardStatus::Active | CardStatus::Terminated) }
+            CardStatus::Terminated => { false }
+        }
+    }
+}
+
+#[allow(dead_code)]
 pub struct Card {
     pub id: i32,
     pub card_type: String,
+    pub status: CardStatus,
+    pub masked_pan: String,
+    pub pan_token: String,
+    pub exp_month: i32,
+    pub exp_year: i32,
     pub created: DateTime<Local>,
     pub acc_id: i32,
     pub cust_id: i32,
+    pub program_id: i32,
 }
 
-#[derive(Deserialize)]
+pub struct Program {
+    pub id: i32,
+    pub bin_from: i64,
+    pub bin_to: i64,
+    pub pan_length: i32,
+    pub expiry_months: i32,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct CreateRequest {
     #[serde(rename = "customerId")]
     pub customer_id: i32,
     #[serde(rename = "accountId")]
     pub account_id: i32,
+    #[serde(rename = "programId")]
+    pub program_id: i32,
 }
 
 #[derive(Serialize)]
 pub struct CreateResponse {
     pub card_id: i32,
+    pub pan: String,
+    pub expiry: String,
 }
 
-pub async fn create_virtual_handler(pool: DBPool, auth: String, req: CreateRequest) -> Result<Json, Rejection> {
-    validate_auth_header(auth);
-    let conn = get_db_conn(&pool).await;
-    match create(&conn, req).await {
-        Ok(id) => {
-            Ok(json(&CreateResponse {
-                card_id: id
-            }))
+impl From<&Card> for CreateResponse {
+    fn from(card: &Card) -> Self {
+        CreateResponse {
+            card_id: card.id,
+            pan: card.masked_pan.clone(),
+            expiry: format!("{:02}/{:02}", card.exp_month, card.exp_year % 100),
         }
-        Err(CardError(message)) => {
-            Ok(json(&ErrorResponse {
-                error: message
-            }))
-        }
-        _ => {
-            Ok(json(&ErrorResponse {
-                error: "general error".to_string()
-            }))
+    }
+}
+
+pub async fn create_virtual_handler(pool: DBPool, merchant: AuthMerchant, idempotency_key: Option<String>,
+                                    req: CreateRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(create(&mut conn, req, merchant.id, idempotency_key).await.map(|card| CreateResponse::from(&card)))
+}
+
+pub async fn create(conn: &mut DBConn, req: CreateRequest, merch_id: i32,
+                    idempotency_key: Option<String>) -> Result<Card, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let request_hash = idempotency::request_hash(&req);
+    if let Some(key) = &idempotency_key {
+        if let Some(id) = idempotency::find(&tx, merch_id, IDEMPOTENCY_SCOPE, key, &request_hash).await? {
+            return get_by_id(&tx, id, merch_id).await;
         }
     }
+    let customer = customer::get_active_by_id(&tx, req.customer_id, merch_id).await?;
+    kyc::check(&tx, &customer, merch_id).await?;
+    account::get_active_by_id_and_merchant(&tx, req.account_id, merch_id).await?;
+    let program = get_program(&tx, req.program_id, merch_id).await?;
+    let number = generate_number(&tx, &program).await?;
+
+    let pan_token = vault::store(&tx, &vault::Secret {
+        pan: number.pan.clone(),
+        cvv: number.cvv.clone(),
+    }).await?;
+
+    let id: i32 = tx.query("insert into card (id, type, status, masked_pan, pan_token, pan_fingerprint, exp_month, \
+     exp_year, created, cust_id, acc_id, program_id) values (default, 'virtual', $1, $2, $3, $4, $5, $6, now(), $7, $8, $9) \
+     returning id",
+                           &[&CardStatus::Active.to_db_val(), &number.masked(), &pan_token,
+                               &vault::fingerprint(&number.pan), &number.exp_month, &number.exp_year,
+                               &req.customer_id, &req.account_id, &program.id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().unwrap().get("id");
+    if let Some(key) = &idempotency_key {
+        idempotency::save(&tx, merch_id, IDEMPOTENCY_SCOPE, key, &request_hash, id).await?;
+    }
+    let card = get_by_id(&tx, id, merch_id).await?;
+    webhook::publish(&tx, merch_id, "card.created", &CreateResponse::from(&card)).await?;
+    transaction::commit(tx).await?;
+    info!("card was created with id: {}",id);
+    Ok(card)
 }
 
-pub async fn create(conn: &DBConn, req: CreateRequest) -> Result<i32, Errors> {
-    let id: i32 = conn.query("insert into card (id, type, created, cust_id, acc_id)\
-     values (default, 'virtual', now(), $1, $2) returning id", &[&req.customer_id, &req.account_id]).await
+async fn get_program<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Program, Errors> {
+    match conn.query("select * from card_program where id = $1 and merch_id = $2", &[&id, &merch_id]).await
         .map_err(|e| {
-            CardError(e.to_string())
-        })?.get(0).unwrap().get("id");
-    info!("customer was created with id: {}",id);
-    Ok(id)
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Err(CardProgramNotFound) }
+        Some(row) => {
+            Ok(Program {
+                id,
+                bin_from: row.get("bin_from"),
+                bin_to: row.get("bin_to"),
+                pan_length: row.get("pan_length"),
+                expiry_months: row.get("expiry_months"),
+            })
+        }
+    }
 }
 
-#[derive(Deserialize)]
+async fn generate_number<C: GenericClient>(conn: &C, program: &Program) -> Result<CardNumber, Errors> {
+    for _ in 0..PAN_ATTEMPTS {
+        let number = CardNumber::generate(program.bin_from, program.bin_to, program.pan_length.max(0) as usize,
+                                          program.expiry_months)
+            .map_err(|e| {
+                InternalError(format!("card program: {} can't issue cards: {}", program.id, e))
+            })?;
+        if conn.query("select id from card where pan_fingerprint = $1", &[&vault::fingerprint(&number.pan)]).await
+            .map_err(|e| {
+                InternalError(e.to_string())
+            })?.is_empty() {
+            return Ok(number);
+        }
+    }
+    Err(InternalError(format!("no unique card number left in program: {}", program.id)))
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct TransactionRequest {
     #[serde(rename = "cardId")]
     pub card_id: i32,
@@ -74,85 +191,450 @@ pub struct TransactionRequest {
     pub order_id: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SpendRequest {
+    #[serde(rename = "cardId")]
+    pub card_id: i32,
+    pub amount: i32,
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    #[serde(flatten)]
+    pub merchant: transaction::MerchantData,
+}
+
 #[derive(Serialize)]
 pub struct TransactionResponse {
     pub trans_id: i32,
 }
 
-pub async fn deposit_virtual_handler(pool: DBPool, auth: String, req: TransactionRequest) -> Result<Json, Rejection> {
-    validate_auth_header(auth);
-    let conn = get_db_conn(&pool).await;
-    match deposit(&conn, req).await {
-        Ok(id) => {
-            Ok(json(&TransactionResponse {
-                trans_id: id
-            }))
-        }
-        Err(CardError(message)) => {
-            Ok(json(&ErrorResponse {
-                error: message
-            }))
-        }
-        Err(TransactionError(message)) => {
-            Ok(json(&ErrorResponse {
-                error: message
-            }))
+// data of the card.funded, card.spent and card.authorized webhook events
+#[derive(Serialize)]
+pub struct CardTransactionEvent<'a> {
+    pub card_id: i32,
+    pub transaction_id: i32,
+    pub amount: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant: Option<&'a transaction::MerchantData>,
+}
+
+pub async fn deposit_virtual_handler(pool: DBPool, merchant: AuthMerchant, req: TransactionRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(deposit(&mut conn, req, merchant.id).await.map(|id| TransactionResponse {
+        trans_id: id
+    }))
+}
+
+pub async fn deposit(conn: &mut DBConn, req: TransactionRequest, merch_id: i32) -> Result<i32, Errors> {
+    if req.amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    let tx = transaction::begin(conn).await?;
+    let order = Order::new(merch_id, req.order_id.clone(), &req);
+    if let Some(trans_id) = transaction::find_by_order(&tx, &VirtualCardDeposit, &order).await? {
+        return Ok(trans_id);
+    }
+    let card = get_active_by_id(&tx, req.card_id, merch_id).await?;
+    let trans_id = transaction::withdraw(&tx, card.acc_id, CARD_ACCOUNT_ID,
+                                         FEE_ACCOUNT_ID, req.amount, VirtualCardDeposit, order).await?;
+    transaction::set_card(&tx, trans_id, card.id).await?;
+    webhook::publish(&tx, merch_id, "card.funded", &CardTransactionEvent {
+        card_id: card.id,
+        transaction_id: trans_id,
+        amount: req.amount,
+        merchant: None,
+    }).await?;
+    transaction::commit(tx).await?;
+    Ok(trans_id)
+}
+
+pub async fn withdraw_virtual_handler(pool: DBPool, merchant: AuthMerchant, req: SpendRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(withdraw(&mut conn, req, merchant.id).await.map(|id| TransactionResponse {
+        trans_id: id
+    }))
+}
+
+pub async fn withdraw(conn: &mut DBConn, req: SpendRequest, merch_id: i32) -> Result<i32, Errors> {
+    let order = Order::new(merch_id, req.order_id.clone(), &req);
+    withdraw_order(conn, req, order, merch_id).await
+}
+
+// a spend keyed by the given order, so spends the API makes on its own, like the ISO 8583 ones, stay out of the
+// merchant's orderIds
+pub async fn withdraw_order(conn: &mut DBConn, req: SpendRequest, order: Order, merch_id: i32) -> Result<i32, Errors> {
+    if req.amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    req.merchant.validate()?;
+    if let Some(trans_id) = transaction::find_by_order(&**conn, &VirtualCardWithdraw, &order).await? {
+        return Ok(trans_id);
+    }
+    let trans_id = create_pending(conn, &req, &VirtualCardWithdraw, &order, merch_id).await?;
+    let posted = post_withdraw(conn, &req, order.pending(trans_id), merch_id).await;
+    fail_unless_posted(conn, trans_id, posted).await
+}
+
+async fn post_withdraw(conn: &mut DBConn, req: &SpendRequest, order: Order, merch_id: i32) -> Result<i32, Errors> {
+    let approval = approve(conn, req, "withdraw", merch_id).await?;
+    let tx = transaction::begin(conn).await?;
+    let card = get_active_by_id(&tx, req.card_id, merch_id).await?;
+    if let Some(trans_id) = transaction::find_by_order(&tx, &VirtualCardWithdraw, &order).await? {
+        return Ok(trans_id);
+    }
+    limit::check(&tx, card.id, req.amount).await?;
+    decision::fund(&tx, &card, &approval, &order, merch_id).await?;
+    let trans_id = transaction::deposit(&tx, card.acc_id, CARD_ACCOUNT_ID,
+                                        FEE_ACCOUNT_ID, req.amount, VirtualCardWithdraw, order).await?;
+    transaction::set_card(&tx, trans_id, card.id).await?;
+    transaction::set_merchant_data(&tx, trans_id, &req.merchant).await?;
+    transaction::set_auth_code(&tx, trans_id).await?;
+    webhook::publish(&tx, merch_id, "card.spent", &CardTransactionEvent {
+        card_id: card.id,
+        transaction_id: trans_id,
+        amount: req.amount,
+        merchant: Some(&req.merchant),
+    }).await?;
+    transaction::commit(tx).await?;
+    Ok(trans_id)
+}
+
+pub async fn authorize_virtual_handler(pool: DBPool, merchant: AuthMerchant, req: SpendRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(authorize(&mut conn, req, merchant.id).await.map(|id| TransactionResponse {
+        trans_id: id
+    }))
+}
+
+pub async fn authorize(conn: &mut DBConn, req: SpendRequest, merch_id: i32) -> Result<i32, Errors> {
+    let order = Order::new(merch_id, req.order_id.clone(), &req);
+    authorize_order(conn, req, order, merch_id).await
+}
+
+pub async fn authorize_order(conn: &mut DBConn, req: SpendRequest, order: Order, merch_id: i32) -> Result<i32, Errors> {
+    if req.amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    req.merchant.validate()?;
+    if let Some(trans_id) = transaction::find_by_order(&**conn, &CardAuthorization, &order).await? {
+        return Ok(trans_id);
+    }
+    let trans_id = create_pending(conn, &req, &CardAuthorization, &order, merch_id).await?;
+    let posted = post_authorization(conn, &req, order.pending(trans_id), merch_id).await;
+    fail_unless_posted(conn, trans_id, posted).await
+}
+
+async fn post_authorization(conn: &mut DBConn, req: &SpendRequest, order: Order, merch_id: i32) -> Result<i32, Errors> {
+    let approval = approve(conn, req, "authorization", merch_id).await?;
+    let tx = transaction::begin(conn).await?;
+    let card = get_active_by_id(&tx, req.card_id, merch_id).await?;
+    if let Some(trans_id) = transaction::find_by_order(&tx, &CardAuthorization, &order).await? {
+        return Ok(trans_id);
+    }
+    limit::check(&tx, card.id, req.amount).await?;
+    decision::fund(&tx, &card, &approval, &order, merch_id).await?;
+    let trans_id = transaction::authorize(&tx, card.acc_id, CARD_ACCOUNT_ID, req.amount, order).await?;
+    transaction::set_card(&tx, trans_id, card.id).await?;
+    transaction::set_merchant_data(&tx, trans_id, &req.merchant).await?;
+    transaction::set_auth_code(&tx, trans_id).await?;
+    webhook::publish(&tx, merch_id, "card.authorized", &CardTransactionEvent {
+        card_id: card.id,
+        transaction_id: trans_id,
+        amount: req.amount,
+        merchant: Some(&req.merchant),
+    }).await?;
+    transaction::commit(tx).await?;
+    Ok(trans_id)
+}
+
+// the spend is recorded as pending on the merchant's own card before anything can decline it
+async fn create_pending(conn: &mut DBConn, req: &SpendRequest, trans_type: &TransactionType, order: &Order,
+                        merch_id: i32) -> Result<i32, Errors> {
+    let card = get_by_id(&**conn, req.card_id, merch_id).await?;
+    transaction::create_pending(conn, trans_type, order, card.id, &req.merchant).await
+}
+
+// a declined or failed spend is kept as failed and answered with the error that stopped it
+async fn fail_unless_posted(conn: &mut DBConn, trans_id: i32, posted: Result<i32, Errors>) -> Result<i32, Errors> {
+    if posted.is_err() {
+        if let Err(e) = transaction::fail(conn, trans_id).await {
+            error!("spend: {} couldn't be marked as failed: {:?}", trans_id, e);
         }
-        _ => {
-            Ok(json(&ErrorResponse {
-                error: "general error".to_string()
-            }))
+    }
+    posted
+}
+
+// the checks that don't need the spend's locks and the merchant's decision, which must not be waited on while they
+// are held
+async fn approve(conn: &DBConn, req: &SpendRequest, spend_type: &str, merch_id: i32) -> Result<decision::Approval, Errors> {
+    let card = get_by_id(&**conn, req.card_id, merch_id).await?;
+    if card.status != CardStatus::Active {
+        return Err(CardNotActive);
+    }
+    restriction::check(&**conn, card.id, &req.merchant).await?;
+    decision::decide(&**conn, &card, req, spend_type, merch_id).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CaptureRequest {
+    pub amount: Option<i32>,
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+}
+
+#[derive(Serialize)]
+pub struct VoidResponse {
+    pub voided: bool,
+}
+
+pub async fn capture_handler(id: i32, pool: DBPool, merchant: AuthMerchant, req: CaptureRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(capture(&mut conn, id, req, merchant.id).await.map(|id| TransactionResponse {
+        trans_id: id
+    }))
+}
+
+pub async fn capture(conn: &mut DBConn, id: i32, req: CaptureRequest, merch_id: i32) -> Result<i32, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let order = Order::new(merch_id, req.order_id.clone(), &(id, &req));
+    let trans_id = transaction::capture(&tx, id, merch_id, req.amount, FEE_ACCOUNT_ID, order).await?;
+    transaction::commit(tx).await?;
+    Ok(trans_id)
+}
+
+pub async fn void_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(void(&mut conn, id, merchant.id).await.map(|_| VoidResponse { voided: true }))
+}
+
+pub async fn void(conn: &mut DBConn, id: i32, merch_id: i32) -> Result<(), Errors> {
+    let tx = transaction::begin(conn).await?;
+    transaction::void(&tx, id, merch_id).await?;
+    transaction::commit(tx).await
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub card_id: i32,
+    pub status: &'static str,
+}
+
+pub async fn freeze_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(set_status(&mut conn, id, merchant.id, CardStatus::Frozen).await)
+}
+
+pub async fn unfreeze_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(set_status(&mut conn, id, merchant.id, CardStatus::Active).await)
+}
+
+pub async fn terminate_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(set_status(&mut conn, id, merchant.id, CardStatus::Terminated).await)
+}
+
+pub async fn set_status(conn: &mut DBConn, id: i32, merch_id: i32, next: CardStatus) -> Result<StatusResponse, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let response = change_status(&tx, id, merch_id, next).await?;
+    transaction::commit(tx).await?;
+    Ok(response)
+}
+
+pub async fn change_status(tx: &Transaction<'_>, id: i32, merch_id: i32, next: CardStatus) -> Result<StatusResponse, Errors> {
+    // the row lock keeps card operations from running while the status changes under them
+    let card = match tx.query("select card.* from card join account on account.id = card.acc_id \
+     where card.id = $1 and account.merch_id = $2 for update of card", &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Err(CardNotFound); }
+        Some(row) => { from_row(row) }
+    };
+    if !card.status.can_transition_to(&next) {
+        return Err(InvalidStatusTransition(format!("card can't change status from {} to {}",
+                                                   card.status.to_db_val(), next.to_db_val())));
+    }
+    // cards of a deactivated customer stay frozen
+    if next == CardStatus::Active && !customer::get_by_id(tx, card.cust_id, merch_id).await?.active {
+        return Err(CustomerNotActive);
+    }
+    tx.execute("update card set status=$1 where id=$2", &[&next.to_db_val(), &id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    if next == CardStatus::Terminated {
+        for hold_id in transaction::find_open_holds(tx, id).await? {
+            transaction::void(tx, hold_id, merch_id).await?;
         }
+        let order = Order::system(merch_id, format!("card-{}-termination", id), &id);
+        transaction::sweep(tx, id, CARD_ACCOUNT_ID, card.acc_id, order).await?;
     }
+    let response = StatusResponse {
+        card_id: id,
+        status: next.to_db_val(),
+    };
+    let event_type = match next {
+        CardStatus::Active => { "card.unfrozen" }
+        CardStatus::Frozen => { "card.frozen" }
+        CardStatus::Terminated => { "card.terminated" }
+    };
+    webhook::publish(tx, merch_id, event_type, &response).await?;
+    info!("card: {} changed status from {} to {}", id, card.status.to_db_val(), next.to_db_val());
+    Ok(response)
 }
 
-pub async fn deposit(conn: &DBConn, req: TransactionRequest) -> Result<i32, Errors> {
-    let card = get_by_id(conn, req.card_id).await?;
-    transaction::withdraw(conn, card.acc_id, CARD_ACCOUNT_ID,
-                          FEE_ACCOUNT_ID, req.amount, VirtualCardDeposit, req.order_id).await
+pub async fn limits_handler(id: i32, pool: DBPool, merchant: AuthMerchant, req: limit::Limits) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(set_limits(&*conn, id, merchant.id, req).await)
+}
+
+pub async fn set_limits<C: GenericClient>(conn: &C, id: i32, merch_id: i32, limits: limit::Limits) -> Result<limit::Limits, Errors> {
+    get_by_id(conn, id, merch_id).await?;
+    limit::save(conn, id, &limits).await?;
+    Ok(limits)
 }
 
-pub async fn withdraw_virtual_handler(pool: DBPool, auth: String, req: TransactionRequest) -> Result<Json, Rejection> {
-    validate_auth_header(auth);
+pub async fn restrictions_handler(id: i32, pool: DBPool, merchant: AuthMerchant,
+                                  req: restriction::Restrictions) -> Result<Response, Rejection> {
     let conn = get_db_conn(&pool).await;
-    match withdraw(&conn, req).await {
-        Ok(id) => {
-            Ok(json(&TransactionResponse {
-                trans_id: id
-            }))
-        }
-        Err(CardError(message)) => {
-            Ok(json(&ErrorResponse {
-                error: message
-            }))
-        }
-        _ => {
-            Ok(json(&ErrorResponse {
-                error: "general error".to_string()
-            }))
+    reply(set_restrictions(&*conn, id, merchant.id, req).await)
+}
+
+pub async fn set_restrictions<C: GenericClient>(conn: &C, id: i32, merch_id: i32,
+                                                restrictions: restriction::Restrictions) -> Result<restriction::Restrictions, Errors> {
+    get_by_id(conn, id, merch_id).await?;
+    restriction::save(conn, id, &restrictions).await?;
+    Ok(restrictions)
+}
+
+#[derive(Serialize)]
+pub struct RevealResponse {
+    pub card_id: i32,
+    pub pan: String,
+    pub cvv: String,
+    pub expiry: String,
+}
+
+pub async fn reveal_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    let result = reveal(&mut conn, id, merchant.id).await;
+    if let Err(e) = &result {
+        // refused attempts are audited too, outside of the rolled back transaction
+        if let Err(audit_err) = audit_reveal(&*conn, id, merchant.id, Some(e.code())).await {
+            error!("reveal of card: {} could not be audited: {:?}", id, audit_err);
         }
     }
+    reply(result)
 }
 
-pub async fn withdraw(conn: &DBConn, req: TransactionRequest) -> Result<i32, Errors> {
-    let card = get_by_id(conn, req.card_id).await?;
-    transaction::deposit(conn, card.acc_id, CARD_ACCOUNT_ID,
-                         FEE_ACCOUNT_ID, req.amount, VirtualCardWithdraw, req.order_id).await
+pub async fn reveal(conn: &mut DBConn, id: i32, merch_id: i32) -> Result<RevealResponse, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let card = get_by_id(&tx, id, merch_id).await?;
+    if card.status == CardStatus::Terminated {
+        return Err(CardNotActive);
+    }
+    let secret = vault::reveal(&tx, &card.pan_token).await?.ok_or(CardAlreadyRevealed)?;
+    audit_reveal(&tx, id, merch_id, None).await?;
+    transaction::commit(tx).await?;
+    Ok(RevealResponse {
+        card_id: id,
+        pan: secret.pan,
+        cvv: secret.cvv,
+        expiry: format!("{:02}/{:02}", card.exp_month, card.exp_year % 100),
+    })
 }
 
-async fn get_by_id(conn: &DBConn, id: i32) -> Result<Card, Errors> {
-    match conn.query("select * from card where id = $1", &[&id]).await.map_err(|e| {
-        CardError(e.to_string())
-    })?.get(0) {
-        None => { Err(CardError("card does not exist".to_string())) }
-        Some(row) => {
-            Ok(Card {
-                id,
-                card_type: row.get("type"),
-                created: row.get("created"),
-                acc_id: row.get("acc_id"),
-                cust_id: row.get("cust_id"),
-            })
-        }
+async fn audit_reveal<C: GenericClient>(conn: &C, id: i32, merch_id: i32, refusal: Option<&str>) -> Result<u64, Errors> {
+    match refusal {
+        None => { info!("card: {} details were revealed to merchant: {}", id, merch_id) }
+        Some(code) => { warn!("card: {} details were refused to merchant: {} with: {}", id, merch_id, code) }
+    }
+    conn.execute("insert into card_reveal_audit (id, card_id, merch_id, revealed, refusal, created) \
+     values (default, $1, $2, $3, $4, now())", &[&id, &merch_id, &refusal.is_none(), &refusal]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })
+}
+
+pub async fn balance_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_balance(&*conn, id, merchant.id).await)
+}
+
+pub async fn get_balance<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<account::BalanceResponse, Errors> {
+    let card = get_by_id(conn, id, merch_id).await?;
+    account::get_balance(conn, card.acc_id, merch_id).await
+}
+
+pub async fn history_handler(id: i32, query: transaction::HistoryQuery, pool: DBPool,
+                             merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_history(&*conn, id, merchant.id, query).await)
+}
+
+pub async fn get_history<C: GenericClient>(conn: &C, id: i32, merch_id: i32,
+                                           query: transaction::HistoryQuery) -> Result<transaction::HistoryResponse, Errors> {
+    let card = get_by_id(conn, id, merch_id).await?;
+    transaction::get_card_history(conn, card.id, query).await
+}
+
+async fn get_by_id<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Card, Errors> {
+    match conn.query("select card.* from card join account on account.id = card.acc_id \
+     where card.id = $1 and account.merch_id = $2", &[&id, &merch_id]).await.map_err(|e| {
+        InternalError(e.to_string())
+    })?.first() {
+        None => { Err(CardNotFound) }
+        Some(row) => { Ok(from_row(row)) }
+    }
+}
+
+pub async fn get_by_pan<C: GenericClient>(conn: &C, pan: &str, merch_id: i32) -> Result<Card, Errors> {
+    match conn.query("select card.* from card join account on account.id = card.acc_id \
+     where card.pan_fingerprint = $1 and account.merch_id = $2", &[&vault::fingerprint(pan), &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Err(CardNotFound) }
+        Some(row) => { Ok(from_row(row)) }
+    }
+}
+
+pub async fn find_by_customer(conn: &Transaction<'_>, cust_id: i32, status: CardStatus) -> Result<Vec<i32>, Errors> {
+    Ok(conn.query("select id from card where cust_id = $1 and status = $2 order by id",
+                  &[&cust_id, &status.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.iter().map(|row| row.get("id")).collect())
+}
+
+// the shared lock lets card operations run side by side but not while the card's status is being changed
+async fn get_active_by_id(conn: &Transaction<'_>, id: i32, merch_id: i32) -> Result<Card, Errors> {
+    let card = match conn.query("select card.* from card join account on account.id = card.acc_id \
+     where card.id = $1 and account.merch_id = $2 for share of card", &[&id, &merch_id]).await.map_err(|e| {
+        InternalError(e.to_string())
+    })?.first() {
+        None => { return Err(CardNotFound); }
+        Some(row) => { from_row(row) }
+    };
+    if card.status != CardStatus::Active {
+        return Err(CardNotActive);
+    }
+    Ok(card)
+}
+
+fn from_row(row: &Row) -> Card {
+    Card {
+        id: row.get("id"),
+        card_type: row.get("type"),
+        status: CardStatus::from_db_val(row.get("status")).unwrap(),
+        masked_pan: row.get("masked_pan"),
+        pan_token: row.get("pan_token"),
+        exp_month: row.get("exp_month"),
+        exp_year: row.get("exp_year"),
+        created: row.get("created"),
+        acc_id: row.get("acc_id"),
+        cust_id: row.get("cust_id"),
+        program_id: row.get("program_id"),
     }
 }
\ No newline at end of file
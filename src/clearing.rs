@@ -0,0 +1,227 @@
+use std::cmp::Ordering;
+use std::fs;
+use serde::Serialize;
+use warp::Rejection;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use tokio_postgres::Transaction;
+use crate::card;
+use crate::db::{DBConn, DBPool, get_db_conn};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{CardNotFound, InvalidClearingFile, TransactionNotFound};
+use crate::token::AuthMerchant;
+use crate::transaction;
+use crate::transaction::{MerchantData, Order, TransactionStatus};
+
+// clearing files are CSV with one presentment per line, amounts are in minor units of the card account currency:
+//   reference,pan,auth_code,amount,mcc,country,merchant_descriptor
+// e.g. "000000000042,4242420012345678,123456,1250,5541,US,SHELL 123 NEW YORK"
+// the descriptor comes last so it may contain commas, auth_code is left empty for presentments nothing was authorized for,
+// blank lines, lines starting with # and a header line starting with "reference" are skipped
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+const FIELD_COUNT: usize = 7;
+const HEADER: &str = "reference";
+
+#[derive(Serialize)]
+pub struct Record {
+    pub reference: String,
+    pub pan: String,
+    pub auth_code: Option<String>,
+    pub amount: i32,
+    pub merchant: MerchantData,
+}
+
+enum Outcome {
+    Duplicate,
+    Captured { hold_id: i32, remaining: i32, excess_id: Option<i32> },
+    ForcePosted { trans_id: i32 },
+    AlreadySettled { trans_id: i32 },
+}
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub records: usize,
+    pub captured: usize,
+    pub force_posted: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub exceptions: Vec<Exception>,
+}
+
+#[derive(Serialize)]
+pub struct Exception {
+    pub line: usize,
+    pub reference: Option<String>,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+pub async fn import_handler(pool: DBPool, merchant: AuthMerchant, body: Bytes) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    let result = match String::from_utf8(body.to_vec()) {
+        Ok(content) => { Ok(import(&mut conn, &content, merchant.id).await) }
+        Err(_) => { Err(InvalidClearingFile("clearing file must be UTF-8 text".to_string())) }
+    };
+    reply(result)
+}
+
+pub async fn import_file(conn: &mut DBConn, path: &str, merch_id: i32) -> Result<Report, Errors> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        InvalidClearingFile(format!("clearing file {} can't be read: {}", path, e))
+    })?;
+    Ok(import(conn, &content, merch_id).await)
+}
+
+// every record is settled in its own transaction, so one bad record doesn't hold back the rest of the file
+// and importing the same file again only reports its records as duplicates
+pub async fn import(conn: &mut DBConn, content: &str, merch_id: i32) -> Report {
+    let mut report = Report::default();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (index == 0 && line.starts_with(HEADER)) {
+            continue;
+        }
+        report.records += 1;
+        let record = match parse(line) {
+            Ok(record) => { record }
+            Err(detail) => {
+                report.failed += 1;
+                report.exceptions.push(Exception { line: index + 1, reference: None, kind: "malformed", detail });
+                continue;
+            }
+        };
+
+        let exception = match settle(conn, &record, merch_id).await {
+            Ok(Outcome::Duplicate) => {
+                report.duplicates += 1;
+                None
+            }
+            Ok(Outcome::Captured { hold_id, remaining, excess_id }) => {
+                report.captured += 1;
+                match (record.amount.cmp(&remaining), excess_id) {
+                    (Ordering::Greater, Some(excess_id)) => {
+                        Some(("amount_mismatch", format!("{} cleared against {} left on authorization {}, \
+                         the difference was force posted by transaction {}", record.amount, remaining, hold_id, excess_id)))
+                    }
+                    (Ordering::Less, _) => {
+                        Some(("amount_mismatch", format!("{} cleared against {} left on authorization {}, \
+                         the rest of the hold was released", record.amount, remaining, hold_id)))
+                    }
+                    _ => { None }
+                }
+            }
+            Ok(Outcome::ForcePosted { trans_id }) => {
+                report.force_posted += 1;
+                Some(("unmatched", format!("no open authorization matches auth code {}, force posted by transaction {}",
+                                           record.auth_code.as_deref().unwrap_or("-"), trans_id)))
+            }
+            Ok(Outcome::AlreadySettled { trans_id }) => {
+                report.failed += 1;
+                Some(("already_settled", format!("auth code {} belongs to transaction {} which can't be captured any more, \
+                 nothing was posted", record.auth_code.as_deref().unwrap_or("-"), trans_id)))
+            }
+            Err(CardNotFound) => {
+                report.failed += 1;
+                Some(("unknown_card", "card number doesn't belong to any card".to_string()))
+            }
+            Err(err) => {
+                if let Errors::InternalError(message) = &err {
+                    error!("clearing record {} failed: {}", record.reference, message);
+                }
+                report.failed += 1;
+                Some(("failed", err.detail()))
+            }
+        };
+        if let Some((kind, detail)) = exception {
+            report.exceptions.push(Exception { line: index + 1, reference: Some(record.reference), kind, detail });
+        }
+    }
+    info!("clearing file with {} records was imported: {} captured, {} force posted, {} duplicates, {} failed",
+          report.records, report.captured, report.force_posted, report.duplicates, report.failed);
+    report
+}
+
+fn parse(line: &str) -> Result<Record, String> {
+    let fields: Vec<&str> = line.splitn(FIELD_COUNT, ',').map(str::trim).collect();
+    if fields.len() != FIELD_COUNT {
+        return Err(format!("record must have {} fields", FIELD_COUNT));
+    }
+    if fields[0].is_empty() {
+        return Err("reference is missing".to_string());
+    }
+    if fields[1].is_empty() || !fields[1].chars().all(|c| c.is_ascii_digit()) {
+        return Err("pan must be digits".to_string());
+    }
+    let amount: i32 = fields[3].parse().ok().filter(|amount| *amount > 0).ok_or("amount must be a positive integer")?;
+    let merchant = MerchantData {
+        descriptor: fields[6].to_string(),
+        mcc: fields[4].to_string(),
+        country: fields[5].to_string(),
+    };
+    merchant.validate().map_err(|e| e.detail())?;
+    Ok(Record {
+        reference: fields[0].to_string(),
+        pan: fields[1].to_string(),
+        auth_code: Some(fields[2].to_string()).filter(|code| !code.is_empty()),
+        amount,
+        merchant,
+    })
+}
+
+// the hold is captured up to the cleared amount: a smaller amount releases the rest of the hold,
+// a larger one is force posted on top. records without an auth code, or with one no spend was given, are force posted
+// as a whole, while an auth code of a spend that is already captured, voided, expired or posted is only reported
+async fn settle(conn: &mut DBConn, record: &Record, merch_id: i32) -> Result<Outcome, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let reference = format!("clearing-{}", record.reference);
+    match transaction::get_by_system_ref(&tx, &reference, merch_id).await {
+        Ok(_) => { return Ok(Outcome::Duplicate); }
+        Err(TransactionNotFound) => {}
+        Err(err) => { return Err(err); }
+    }
+    let card = card::get_by_pan(&tx, &record.pan, merch_id).await?;
+    let hold = match &record.auth_code {
+        None => { None }
+        Some(auth_code) => {
+            let hold = transaction::find_hold(&tx, card.id, auth_code, merch_id).await?;
+            if hold.is_none() {
+                if let Some(trans_id) = transaction::find_closed_spend(&tx, card.id, auth_code).await? {
+                    return Ok(Outcome::AlreadySettled { trans_id });
+                }
+            }
+            hold
+        }
+    };
+
+    let outcome = match hold {
+        None => {
+            let order = Order::system(merch_id, reference, record);
+            let trans_id = force_post(&tx, &card, record.amount, &record.merchant, order).await?;
+            Outcome::ForcePosted { trans_id }
+        }
+        Some(hold) => {
+            let remaining = hold.remaining();
+            let order = Order::system(merch_id, reference.clone(), record);
+            transaction::capture(&tx, hold.id, merch_id, Some(record.amount.min(remaining)), card::FEE_ACCOUNT_ID,
+                                 order).await?;
+            let mut excess_id = None;
+            if record.amount < remaining {
+                transaction::set_status(&tx, hold.id, TransactionStatus::Captured).await?;
+            } else if record.amount > remaining {
+                let order = Order::system(merch_id, format!("{}-excess", reference), record);
+                excess_id = Some(force_post(&tx, &card, record.amount - remaining, &record.merchant, order).await?);
+            }
+            Outcome::Captured { hold_id: hold.id, remaining, excess_id }
+        }
+    };
+    transaction::commit(tx).await?;
+    Ok(outcome)
+}
+
+async fn force_post(tx: &Transaction<'_>, card: &card::Card, amount: i32, merchant: &MerchantData,
+                    order: Order) -> Result<i32, Errors> {
+    let trans_id = transaction::force_post(tx, card.acc_id, card::CARD_ACCOUNT_ID, amount, order).await?;
+    transaction::set_card(tx, trans_id, card.id).await?;
+    transaction::set_merchant_data(tx, trans_id, merchant).await?;
+    Ok(trans_id)
+}
@@ -1,13 +1,56 @@
 use chrono::prelude::*;
 use crate::db::{DBPool, get_db_conn, DBConn};
-use crate::token::validate_auth_header;
-use crate::Errors::CustomerError;
-use crate::{Errors, ErrorResponse};
+use crate::token::AuthMerchant;
+use crate::error::{Errors, reply};
+use crate::error::Errors::{CustomerNotActive, CustomerNotFound, InternalError, InvalidFilter};
+use crate::{card, idempotency, kyc, transaction, webhook};
+use crate::validation::{Validator, MAX_ADDRESS_LENGTH, MAX_CITY_LENGTH, MAX_NAME_LENGTH, MAX_STATE_REGION_LENGTH};
+use crate::card::CardStatus;
+use crate::kyc::KycStatus;
 use serde::{Serialize, Deserialize};
-use warp::reply::{Json, json};
+use warp::reply::Response;
 use warp::Rejection;
+use tokio_postgres::{GenericClient, Row};
 
-#[derive(Deserialize)]
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[allow(dead_code)]
+#[derive(Serialize)]
+pub struct Customer {
+    pub id: i32,
+    pub phone: String,
+    pub email: String,
+    pub active: bool,
+    pub first_name: String,
+    pub last_name: String,
+    pub birth_date: NaiveDate,
+    pub address: String,
+    pub address2: Option<String>,
+    pub city: String,
+    pub state_region: Option<String>,
+    pub country: String,
+    pub postal_code: String,
+    pub kyc_status: String,
+    pub kyc_reason: Option<String>,
+    #[serde(skip)]
+    pub merch_id: i32,
+}
+
+const IDEMPOTENCY_SCOPE: &str = "customer";
+
+impl Customer {
+    // the details a verification was done against, contact details can change without one
+    fn same_identity(&self, other: &Customer) -> bool {
+        self.first_name == other.first_name && self.last_name == other.last_name
+            && self.birth_date == other.birth_date && self.address == other.address
+            && self.address2 == other.address2 && self.city == other.city
+            && self.state_region == other.state_region && self.country == other.country
+            && self.postal_code == other.postal_code
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct CreateRequest {
     pub phone: String,
     pub email: String,
@@ -18,6 +61,9 @@ pub struct CreateRequest {
     #[serde(rename="birthDate")]
     pub birth_date: String,
     pub address: String,
+    // left out of the request hash when missing, so requests from before it existed still replay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address2: Option<String>,
     pub city: String,
     #[serde(rename="stateRegion")]
     pub state_region: String,
@@ -26,43 +72,301 @@ pub struct CreateRequest {
     pub postal_code: String,
 }
 
+// fields that are left out stay as they are, an empty address2 or stateRegion removes it
+#[derive(Deserialize)]
+pub struct UpdateRequest {
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    #[serde(rename="firstName")]
+    pub first_name: Option<String>,
+    #[serde(rename="lastName")]
+    pub last_name: Option<String>,
+    #[serde(rename="birthDate")]
+    pub birth_date: Option<String>,
+    pub address: Option<String>,
+    pub address2: Option<String>,
+    pub city: Option<String>,
+    #[serde(rename="stateRegion")]
+    pub state_region: Option<String>,
+    pub country: Option<String>,
+    #[serde(rename="postalCode")]
+    pub postal_code: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct CreateResponse {
     pub customer_id: i32,
 }
 
-pub async fn create_handler(pool: DBPool, auth: String, req: CreateRequest) -> Result<Json, Rejection> {
-    let merchant_id = validate_auth_header(auth);
-    let conn = get_db_conn(&pool).await;
-    match create(&conn, req, merchant_id).await {
-        Ok(id) => {
-            Ok(json(&CreateResponse {
-                customer_id: id
-            }))
+#[derive(Serialize)]
+pub struct DeactivateResponse {
+    pub customer_id: i32,
+    pub frozen_cards: Vec<i32>,
+}
+
+// email matches regardless of case, name matches any part of "<first name> <last name>"
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub name: Option<String>,
+    pub active: Option<bool>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    pub customers: Vec<Customer>,
+    pub next_cursor: Option<String>,
+}
+
+pub async fn create_handler(pool: DBPool, merchant: AuthMerchant, idempotency_key: Option<String>,
+                            req: CreateRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(create(&mut conn, req, merchant.id, idempotency_key).await.map(|id| CreateResponse {
+        customer_id: id
+    }))
+}
+
+pub async fn create(conn: &mut DBConn, req: CreateRequest, merch_id: i32,
+                    idempotency_key: Option<String>) -> Result<i32, Errors> {
+    let mut validator = Validator::default();
+    let phone = validator.phone("phone", &req.phone);
+    let email = validator.email("email", &req.email);
+    let first_name = validator.text("firstName", &req.first_name, MAX_NAME_LENGTH);
+    let last_name = validator.text("lastName", &req.last_name, MAX_NAME_LENGTH);
+    let birth_date = validator.birth_date("birthDate", &req.birth_date, Utc::today().naive_utc());
+    let address = validator.text("address", &req.address, MAX_ADDRESS_LENGTH);
+    let address2 = req.address2.as_deref().and_then(|address2| {
+        validator.optional_text("address2", address2, MAX_ADDRESS_LENGTH)
+    });
+    let city = validator.text("city", &req.city, MAX_CITY_LENGTH);
+    let state_region = validator.optional_text("stateRegion", &req.state_region, MAX_STATE_REGION_LENGTH);
+    let country = validator.country("country", &req.country);
+    let postal_code = validator.postal_code("postalCode", &country, &req.postal_code);
+    validator.finish()?;
+
+    let tx = transaction::begin(conn).await?;
+    let request_hash = idempotency::request_hash(&req);
+    if let Some(key) = &idempotency_key {
+        if let Some(id) = idempotency::find(&tx, merch_id, IDEMPOTENCY_SCOPE, key, &request_hash).await? {
+            return Ok(id);
         }
-        Err(CustomerError(message)) => {
-            Ok(json(&ErrorResponse {
-                error: message
-            }))
+    }
+
+    let id: i32 = tx.query("insert into customer\
+     (id, phone, email, active, first_name, last_name, birth_date, address, address2, city, state_region, country, \
+     postal_code, kyc_status, merch_id) values\
+       (default, $1, $2, true, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) returning id",
+                             &[&phone, &email, &first_name, &last_name, &birth_date, &address, &address2, &city,
+                                 &state_region, &country, &postal_code, &KycStatus::Unverified.to_db_val(),
+                                 &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().unwrap().get("id");
+    if let Some(key) = &idempotency_key {
+        idempotency::save(&tx, merch_id, IDEMPOTENCY_SCOPE, key, &request_hash, id).await?;
+    }
+    webhook::publish(&tx, merch_id, "customer.created", &CreateResponse { customer_id: id }).await?;
+    transaction::commit(tx).await?;
+    info!("customer was created with id: {}", id);
+    Ok(id)
+}
+
+pub async fn get_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_by_id(&*conn, id, merchant.id).await)
+}
+
+pub async fn update_handler(id: i32, pool: DBPool, merchant: AuthMerchant, req: UpdateRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(update(&mut conn, id, req, merchant.id).await)
+}
+
+pub async fn update(conn: &mut DBConn, id: i32, req: UpdateRequest, merch_id: i32) -> Result<Customer, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let customer = lock_by_id(&tx, id, merch_id).await?;
+    if !customer.active {
+        return Err(CustomerNotActive);
+    }
+
+    let mut validator = Validator::default();
+    let phone = req.phone.as_deref().map(|phone| validator.phone("phone", phone));
+    let email = req.email.as_deref().map(|email| validator.email("email", email));
+    let first_name = req.first_name.as_deref().map(|name| validator.text("firstName", name, MAX_NAME_LENGTH));
+    let last_name = req.last_name.as_deref().map(|name| validator.text("lastName", name, MAX_NAME_LENGTH));
+    let birth_date = req.birth_date.as_deref().map(|birth_date| {
+        validator.birth_date("birthDate", birth_date, Utc::today().naive_utc())
+    });
+    let address = req.address.as_deref().map(|address| validator.text("address", address, MAX_ADDRESS_LENGTH));
+    let address2 = req.address2.as_deref().map(|address2| {
+        validator.optional_text("address2", address2, MAX_ADDRESS_LENGTH).unwrap_or_default()
+    });
+    let city = req.city.as_deref().map(|city| validator.text("city", city, MAX_CITY_LENGTH));
+    let state_region = req.state_region.as_deref().map(|state_region| {
+        validator.optional_text("stateRegion", state_region, MAX_STATE_REGION_LENGTH).unwrap_or_default()
+    });
+    let country = req.country.as_deref().map(|country| validator.country("country", country));
+    // a new country can make the postal code already on file invalid, so it's checked again
+    let postal_code = if country.is_some() || req.postal_code.is_some() {
+        Some(validator.postal_code("postalCode", country.as_deref().unwrap_or(&customer.country),
+                                   req.postal_code.as_deref().unwrap_or(&customer.postal_code)))
+    } else {
+        None
+    };
+    validator.finish()?;
+
+    let rows = tx.query("update customer set phone = coalesce($1, phone), email = coalesce($2, email), \
+     first_name = coalesce($3, first_name), last_name = coalesce($4, last_name), \
+     birth_date = coalesce($5, birth_date), address = coalesce($6, address), \
+     address2 = case when $7::varchar is null then address2 else nullif($7, '') end, city = coalesce($8, city), \
+     state_region = case when $9::varchar is null then state_region else nullif($9, '') end, \
+     country = coalesce($10, country), postal_code = coalesce($11, postal_code) where id = $12 returning *",
+                        &[&phone, &email, &first_name, &last_name, &birth_date, &address, &address2, &city,
+                            &state_region, &country, &postal_code, &id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let mut updated = from_row(rows.first().ok_or(CustomerNotFound)?);
+    webhook::publish(&tx, merch_id, "customer.updated", &CreateResponse { customer_id: id }).await?;
+    if !updated.same_identity(&customer) && updated.kyc_status != KycStatus::Unverified.to_db_val() {
+        kyc::reset(&tx, id, merch_id).await?;
+        updated = get_by_id(&tx, id, merch_id).await?;
+        info!("customer: {} has to be verified again", id);
+    }
+    transaction::commit(tx).await?;
+    info!("customer: {} was updated", id);
+    Ok(updated)
+}
+
+pub async fn deactivate_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(deactivate(&mut conn, id, merchant.id).await)
+}
+
+// the customer's active cards are frozen along with it, deactivating again changes nothing
+pub async fn deactivate(conn: &mut DBConn, id: i32, merch_id: i32) -> Result<DeactivateResponse, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let mut frozen_cards = Vec::new();
+    if lock_by_id(&tx, id, merch_id).await?.active {
+        tx.execute("update customer set active = false where id = $1", &[&id]).await
+            .map_err(|e| {
+                InternalError(e.to_string())
+            })?;
+        for card_id in card::find_by_customer(&tx, id, CardStatus::Active).await? {
+            card::change_status(&tx, card_id, merch_id, CardStatus::Frozen).await?;
+            frozen_cards.push(card_id);
         }
-        _ => { Ok(json(&ErrorResponse { error: "general error".to_string() })) }
+        webhook::publish(&tx, merch_id, "customer.deactivated", &CreateResponse { customer_id: id }).await?;
+        info!("customer: {} was deactivated, {} cards were frozen", id, frozen_cards.len());
+    }
+    transaction::commit(tx).await?;
+    Ok(DeactivateResponse {
+        customer_id: id,
+        frozen_cards,
+    })
+}
+
+pub async fn search_handler(query: SearchQuery, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(search(&*conn, merchant.id, query).await)
+}
+
+pub async fn search<C: GenericClient>(conn: &C, merch_id: i32, query: SearchQuery) -> Result<SearchResponse, Errors> {
+    let before_id = match &query.cursor {
+        None => { None }
+        Some(cursor) => { Some(transaction::decode_cursor(cursor)?) }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(InvalidFilter(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
     }
+    // phones are stored normalized, so the filter is normalized the same way
+    let mut validator = Validator::default();
+    let phone = query.phone.as_deref().map(|phone| validator.phone("phone", phone));
+    validator.finish()?;
+    // the name is matched literally, so % and _ in it aren't wildcards
+    let name = query.name.as_ref().map(|name| {
+        format!("%{}%", name.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+    });
+
+    // one extra row is fetched to find out whether there is a next page
+    let rows = conn.query("select * from customer where merch_id = $1 \
+     and ($2::varchar is null or lower(email) = lower($2)) and ($3::varchar is null or phone = $3) \
+     and ($4::varchar is null or first_name || ' ' || last_name ilike $4) \
+     and ($5::boolean is null or active = $5) and ($6::integer is null or id < $6) \
+     order by id desc limit $7",
+                          &[&merch_id, &query.email, &phone, &name, &query.active, &before_id,
+                              &(limit + 1)]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    let mut customers: Vec<Customer> = rows.iter().map(from_row).collect();
+    let next_cursor = if customers.len() as i64 > limit {
+        customers.truncate(limit as usize);
+        customers.last().map(|customer| transaction::encode_cursor(customer.id))
+    } else {
+        None
+    };
+    Ok(SearchResponse {
+        customers,
+        next_cursor,
+    })
 }
 
-pub async fn create(conn: &DBConn, req: CreateRequest, merch_id: i32) -> Result<i32, Errors> {
-    let birth_date=NaiveDate::parse_from_str(&req.birth_date,"%Y-%m-%d").map_err(|_| {
-        CustomerError("birthDate is not valid".to_string())
-    })?;
+pub async fn get_by_id<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Customer, Errors> {
+    match conn.query("select * from customer where id=$1 and merch_id=$2", &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Err(CustomerNotFound) }
+        Some(row) => { Ok(from_row(row)) }
+    }
+}
 
-    let id: i32 = conn.query("insert into customer\
-     (id, phone, email, active, first_name, last_name, birth_date, address, city, state_region, country, postal_code, merch_id) values\
-       (default, $1, $2, true, $3, $4, $5, $6, $7, $8, $9, $10, $11) returning id",
-                             &[&req.phone, &req.email, &req.first_name, &req.last_name, &birth_date,
-                                 &req.address, &req.city, &req.state_region, &req.country, &req.postal_code, &merch_id]).await
+pub async fn get_active_by_id<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Customer, Errors> {
+    match conn.query("select * from customer where id=$1 and merch_id=$2 and active = true", &[&id, &merch_id]).await
         .map_err(|e| {
-            CustomerError(e.to_string())
-        })?.get(0).unwrap().get("id");
-    info!("customer was created with id: {}", id);
-    Ok(id)
+            InternalError(e.to_string())
+        })?.first() {
+        None => {
+            Err(CustomerNotFound)
+        }
+        Some(row) => {
+            Ok(from_row(row))
+        }
+    }
+}
+
+pub async fn lock_by_id<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<Customer, Errors> {
+    match conn.query("select * from customer where id=$1 and merch_id=$2 for update", &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Err(CustomerNotFound) }
+        Some(row) => { Ok(from_row(row)) }
+    }
 }
 
+fn from_row(row: &Row) -> Customer {
+    Customer {
+        id: row.get("id"),
+        phone: row.get("phone"),
+        email: row.get("email"),
+        active: row.get::<_, Option<bool>>("active").unwrap_or(false),
+        first_name: row.get("first_name"),
+        last_name: row.get("last_name"),
+        birth_date: row.get("birth_date"),
+        address: row.get("address"),
+        address2: row.get("address2"),
+        city: row.get("city"),
+        state_region: row.get("state_region"),
+        country: row.get("country"),
+        postal_code: row.get("postal_code"),
+        kyc_status: row.get("kyc_status"),
+        kyc_reason: row.get("kyc_reason"),
+        merch_id: row.get("merch_id"),
+    }
+}
@@ -0,0 +1,282 @@
+use std::env;
+use std::sync::OnceLock;
+use std::time::Duration;
+use hyper::{Body, Method, Request};
+use hyper::header::CONTENT_TYPE;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use serde::{Serialize, Deserialize};
+use tokio_postgres::{GenericClient, Row, Transaction};
+use warp::reply::Response;
+use warp::Rejection;
+use crate::account;
+use crate::card::{Card, SpendRequest, CARD_ACCOUNT_ID, FEE_ACCOUNT_ID};
+use crate::db::{DBPool, get_db_conn};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{AuthorizationDeclined, InternalError, InvalidDecisionConfig};
+use crate::token::AuthMerchant;
+use crate::{outbound, transaction, webhook};
+use crate::transaction::{MerchantData, Order, TransactionType};
+
+// merchants in remote decisioning mode get every card spend POSTed to their endpoint before it's posted, e.g.
+// {"type": "authorization", "cardId": 1, "orderId": "a1", "amount": 1250, "available": 300,
+//  "merchantDescriptor": "SHOP", "mcc": "5411", "country": "US"}
+// and answer with {"decision": "approve", "fundingAmount": 1000} or {"decision": "decline", "reason": "..."},
+// fundingAmount is optional and moved from the configured funding account onto the card account just in time.
+// requests carry the same Webhook-Signature header as webhook events, keyed with the secret returned on save.
+// endpoints must be https on a public address, anything else going wrong within the timeout gets the configured
+// default decision. AUTHORIZATION_WEBHOOK_ALLOW_LOCAL=true lets http and local addresses through for development
+const ALLOW_LOCAL_VAR: &str = "AUTHORIZATION_WEBHOOK_ALLOW_LOCAL";
+const MAX_TIMEOUT_MS: i32 = 10_000;
+const SYSTEM_ACCOUNT_IDS: [i32; 3] = [account::CASH_ACCOUNT_ID, CARD_ACCOUNT_ID, FEE_ACCOUNT_ID];
+
+static CLIENT: OnceLock<outbound::Client> = OnceLock::new();
+
+#[derive(PartialEq)]
+pub enum Decision {
+    Approve,
+    Decline,
+}
+
+impl Decision {
+    fn to_db_val(&self) -> &'static str {
+        match self {
+            Decision::Approve => { "approve" }
+            Decision::Decline => { "decline" }
+        }
+    }
+
+    fn from_db_val(val: &str) -> Option<Decision> {
+        match val {
+            "approve" => { Some(Decision::Approve) }
+            "decline" => { Some(Decision::Decline) }
+            _ => { None }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    pub url: String,
+    #[serde(rename = "timeoutMs")]
+    pub timeout_ms: i32,
+    #[serde(rename = "defaultDecision")]
+    pub default_decision: String,
+    #[serde(rename = "fundingAccountId")]
+    pub funding_account_id: Option<i32>,
+    // kept when the webhook is changed, so endpoints don't have to be rekeyed
+    #[serde(skip_deserializing)]
+    pub secret: String,
+}
+
+impl Config {
+    fn from_row(row: &Row) -> Config {
+        Config {
+            url: row.get("url"),
+            timeout_ms: row.get("timeout_ms"),
+            default_decision: row.get("default_decision"),
+            funding_account_id: row.get("funding_acc_id"),
+            secret: row.get("secret"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Errors> {
+        outbound::check_url(&self.url, allow_local()).map_err(InvalidDecisionConfig)?;
+        if !(1..=MAX_TIMEOUT_MS).contains(&self.timeout_ms) {
+            return Err(InvalidDecisionConfig(format!("timeoutMs must be between 1 and {}", MAX_TIMEOUT_MS)));
+        }
+        if Decision::from_db_val(&self.default_decision).is_none() {
+            return Err(InvalidDecisionConfig(format!("defaultDecision must be {} or {}",
+                                                    Decision::Approve.to_db_val(), Decision::Decline.to_db_val())));
+        }
+        if self.funding_account_id.is_some_and(|account_id| SYSTEM_ACCOUNT_IDS.contains(&account_id)) {
+            return Err(InvalidDecisionConfig("fundingAccountId can't be a system account".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// what the merchant approved, the funding is only posted once the spend's transaction has started
+#[derive(Default)]
+pub struct Approval {
+    funding: Option<Funding>,
+}
+
+struct Funding {
+    account_id: i32,
+    amount: i32,
+}
+
+#[derive(Serialize)]
+pub struct DeleteResponse {
+    pub deleted: bool,
+}
+
+#[derive(Serialize)]
+struct DecisionRequest<'a> {
+    #[serde(rename = "type")]
+    spend_type: &'a str,
+    #[serde(rename = "cardId")]
+    card_id: i32,
+    #[serde(rename = "orderId")]
+    order_id: &'a str,
+    amount: i32,
+    available: i64,
+    #[serde(flatten)]
+    merchant: &'a MerchantData,
+}
+
+#[derive(Deserialize)]
+struct DecisionResponse {
+    decision: String,
+    #[serde(rename = "fundingAmount")]
+    funding_amount: Option<i32>,
+    reason: Option<String>,
+}
+
+pub async fn config_handler(pool: DBPool, merchant: AuthMerchant, req: Config) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(save(&*conn, merchant.id, req).await)
+}
+
+pub async fn delete_handler(pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(delete(&*conn, merchant.id).await.map(|deleted| DeleteResponse { deleted }))
+}
+
+pub async fn save<C: GenericClient>(conn: &C, merch_id: i32, mut config: Config) -> Result<Config, Errors> {
+    config.validate()?;
+    if let Some(account_id) = config.funding_account_id {
+        account::get_active_by_id_and_merchant(conn, account_id, merch_id).await?;
+    }
+    let secret: String = rand::thread_rng().sample_iter(&Alphanumeric).take(webhook::SECRET_LENGTH)
+        .map(char::from).collect();
+    config.secret = conn.query("insert into authorization_webhook \
+     (merch_id, url, timeout_ms, default_decision, funding_acc_id, secret, updated) values ($1, $2, $3, $4, $5, $6, now()) \
+     on conflict (merch_id) do update set url = $2, timeout_ms = $3, default_decision = $4, funding_acc_id = $5, \
+     updated = now() returning secret",
+                               &[&merch_id, &config.url, &config.timeout_ms, &config.default_decision,
+                                   &config.funding_account_id, &secret]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().unwrap().get("secret");
+    info!("authorization webhook of merchant: {} was set to {}", merch_id, config.url);
+    Ok(config)
+}
+
+pub async fn delete<C: GenericClient>(conn: &C, merch_id: i32) -> Result<bool, Errors> {
+    let deleted = conn.execute("delete from authorization_webhook where merch_id = $1", &[&merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    info!("authorization webhook of merchant: {} was removed", merch_id);
+    Ok(deleted > 0)
+}
+
+// asks the merchant whether the spend may go ahead, merchants without a webhook decide nothing.
+// it's called before the spend's transaction starts so no lock is held while waiting, the available balance sent
+// along can therefore be out of date by the time the spend is posted
+pub async fn decide<C: GenericClient>(conn: &C, card: &Card, req: &SpendRequest, spend_type: &str,
+                                      merch_id: i32) -> Result<Approval, Errors> {
+    let config = match conn.query("select * from authorization_webhook where merch_id = $1", &[&merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Ok(Approval::default()); }
+        Some(row) => { Config::from_row(row) }
+    };
+
+    let payload = DecisionRequest {
+        spend_type,
+        card_id: card.id,
+        order_id: &req.order_id,
+        amount: req.amount,
+        available: transaction::get_sum(conn, card.acc_id).await?,
+        merchant: &req.merchant,
+    };
+    let timeout = Duration::from_millis(config.timeout_ms as u64);
+    let response = match tokio::time::timeout(timeout, call(&config.url, &config.secret, &payload)).await {
+        Ok(Ok(response)) => { response }
+        Ok(Err(e)) => {
+            warn!("authorization webhook of merchant: {} failed, {} applies: {}", merch_id, config.default_decision, e);
+            return apply_default(&config);
+        }
+        Err(_) => {
+            warn!("authorization webhook of merchant: {} timed out, {} applies", merch_id, config.default_decision);
+            return apply_default(&config);
+        }
+    };
+
+    match Decision::from_db_val(&response.decision) {
+        Some(Decision::Approve) => {}
+        Some(Decision::Decline) => {
+            info!("spend with orderId: {} was declined by merchant: {}", req.order_id, merch_id);
+            return Err(AuthorizationDeclined(response.reason.unwrap_or_else(|| {
+                "merchant declined the authorization".to_string()
+            })));
+        }
+        None => {
+            warn!("authorization webhook of merchant: {} answered {}, {} applies", merch_id, response.decision,
+                  config.default_decision);
+            return apply_default(&config);
+        }
+    }
+
+    let funding = match (response.funding_amount.filter(|amount| *amount > 0), config.funding_account_id) {
+        (Some(amount), Some(account_id)) => { Some(Funding { account_id, amount }) }
+        (Some(_), None) => {
+            warn!("merchant: {} asked for funding without a funding account", merch_id);
+            None
+        }
+        (None, _) => { None }
+    };
+    Ok(Approval { funding })
+}
+
+// posts the funding the merchant asked for in the spend's transaction, right before the spend itself.
+// it's keyed after the spend, apart from the merchant's orderIds and from the spends the API makes on its own
+pub async fn fund(conn: &Transaction<'_>, card: &Card, approval: &Approval, spend: &Order,
+                  merch_id: i32) -> Result<(), Errors> {
+    if let Some(funding) = &approval.funding {
+        let reference = if spend.system {
+            format!("jit-{}", spend.order_id)
+        } else {
+            format!("jit-order-{}", spend.order_id)
+        };
+        let order = Order::system(merch_id, reference, &(card.id, funding.amount));
+        let trans_id = transaction::deposit(conn, funding.account_id, card.acc_id, FEE_ACCOUNT_ID, funding.amount,
+                                            TransactionType::JitFunding, order).await?;
+        transaction::set_card(conn, trans_id, card.id).await?;
+        info!("card: {} was funded with {} by transaction: {}", card.id, funding.amount, trans_id);
+    }
+    Ok(())
+}
+
+fn apply_default(config: &Config) -> Result<Approval, Errors> {
+    match Decision::from_db_val(&config.default_decision) {
+        Some(Decision::Approve) => { Ok(Approval::default()) }
+        _ => { Err(AuthorizationDeclined("merchant endpoint didn't answer the authorization".to_string())) }
+    }
+}
+
+fn allow_local() -> bool {
+    env::var(ALLOW_LOCAL_VAR).is_ok_and(|val| val == "true")
+}
+
+fn client() -> &'static outbound::Client {
+    CLIENT.get_or_init(|| outbound::client(allow_local()))
+}
+
+async fn call(url: &str, secret: &str, payload: &DecisionRequest<'_>) -> Result<DecisionResponse, String> {
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let request = Request::builder().method(Method::POST).uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(webhook::SIGNATURE_HEADER, webhook::sign(secret, &body)?)
+        .body(Body::from(body)).map_err(|e| e.to_string())?;
+    let response = client().request(request).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("endpoint answered with status {}", response.status()));
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map_err(|e| e.to_string())
+}
@@ -0,0 +1,220 @@
+use serde::Serialize;
+use warp::{Rejection, Reply};
+use warp::http::StatusCode;
+use warp::http::header::{CONTENT_TYPE, HeaderValue};
+use warp::reject::Reject;
+use warp::reply::{Response, json, with_status};
+use std::convert::Infallible;
+use crate::validation::FieldError;
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+#[derive(Debug)]
+pub enum Errors {
+    Unauthorized(String),
+    InvalidCredentials,
+    MerchantNotFound,
+    AccountNotFound,
+    CustomerNotFound,
+    CardNotFound,
+    CardProgramNotFound,
+    TransactionNotFound,
+    WebhookEndpointNotFound,
+    WebhookEventNotFound,
+    InsufficientFunds,
+    CardNotActive,
+    CustomerNotActive,
+    CustomerNotVerified,
+    CardAlreadyRevealed,
+    LimitExceeded(String),
+    CardRestricted(String),
+    AuthorizationDeclined(String),
+    InvalidMerchantData(String),
+    CurrencyMismatch,
+    InvalidFields(Vec<FieldError>),
+    InvalidAmount(String),
+    InvalidLimit(String),
+    InvalidFilter(String),
+    InvalidClearingFile(String),
+    InvalidDecisionConfig(String),
+    InvalidWebhookEndpoint(String),
+    InvalidKycDocument(String),
+    IdempotencyConflict(String),
+    InvalidStatusTransition(String),
+    NotReversible(String),
+    RefundExceedsOriginal,
+    CaptureExceedsAuthorization,
+    InternalError(String),
+}
+
+impl Reject for Errors {}
+
+impl Errors {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::Unauthorized(_) => { "unauthorized" }
+            Errors::InvalidCredentials => { "invalid_credentials" }
+            Errors::MerchantNotFound => { "merchant_not_found" }
+            Errors::AccountNotFound => { "account_not_found" }
+            Errors::CustomerNotFound => { "customer_not_found" }
+            Errors::CardNotFound => { "card_not_found" }
+            Errors::CardProgramNotFound => { "card_program_not_found" }
+            Errors::TransactionNotFound => { "transaction_not_found" }
+            Errors::WebhookEndpointNotFound => { "webhook_endpoint_not_found" }
+            Errors::WebhookEventNotFound => { "webhook_event_not_found" }
+            Errors::InsufficientFunds => { "insufficient_funds" }
+            Errors::CardNotActive => { "card_not_active" }
+            Errors::CustomerNotActive => { "customer_not_active" }
+            Errors::CustomerNotVerified => { "customer_not_verified" }
+            Errors::CardAlreadyRevealed => { "card_already_revealed" }
+            Errors::LimitExceeded(_) => { "limit_exceeded" }
+            Errors::CardRestricted(_) => { "card_restricted" }
+            Errors::AuthorizationDeclined(_) => { "authorization_declined" }
+            Errors::InvalidMerchantData(_) => { "invalid_merchant_data" }
+            Errors::CurrencyMismatch => { "currency_mismatch" }
+            Errors::InvalidFields(_) => { "invalid_fields" }
+            Errors::InvalidAmount(_) => { "invalid_amount" }
+            Errors::InvalidLimit(_) => { "invalid_limit" }
+            Errors::InvalidFilter(_) => { "invalid_filter" }
+            Errors::InvalidClearingFile(_) => { "invalid_clearing_file" }
+            Errors::InvalidDecisionConfig(_) => { "invalid_decision_config" }
+            Errors::InvalidWebhookEndpoint(_) => { "invalid_webhook_endpoint" }
+            Errors::InvalidKycDocument(_) => { "invalid_kyc_document" }
+            Errors::IdempotencyConflict(_) => { "idempotency_conflict" }
+            Errors::InvalidStatusTransition(_) => { "invalid_status_transition" }
+            Errors::NotReversible(_) => { "transaction_not_reversible" }
+            Errors::RefundExceedsOriginal => { "refund_exceeds_original" }
+            Errors::CaptureExceedsAuthorization => { "capture_exceeds_authorization" }
+            Errors::InternalError(_) => { "internal_error" }
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Errors::Unauthorized(_) | Errors::InvalidCredentials => { StatusCode::UNAUTHORIZED }
+            Errors::MerchantNotFound | Errors::AccountNotFound | Errors::CustomerNotFound
+            | Errors::CardNotFound | Errors::CardProgramNotFound | Errors::TransactionNotFound
+            | Errors::WebhookEndpointNotFound | Errors::WebhookEventNotFound => { StatusCode::NOT_FOUND }
+            Errors::InsufficientFunds | Errors::CardNotActive | Errors::CustomerNotActive | Errors::CustomerNotVerified
+            | Errors::LimitExceeded(_) | Errors::CardRestricted(_)
+            | Errors::AuthorizationDeclined(_) | Errors::CurrencyMismatch
+            | Errors::RefundExceedsOriginal | Errors::CaptureExceedsAuthorization => { StatusCode::UNPROCESSABLE_ENTITY }
+            Errors::InvalidFields(_) | Errors::InvalidAmount(_) | Errors::InvalidLimit(_) | Errors::InvalidFilter(_)
+            | Errors::InvalidMerchantData(_) | Errors::InvalidClearingFile(_)
+            | Errors::InvalidDecisionConfig(_) | Errors::InvalidWebhookEndpoint(_)
+            | Errors::InvalidKycDocument(_) => { StatusCode::BAD_REQUEST }
+            Errors::IdempotencyConflict(_) | Errors::InvalidStatusTransition(_)
+            | Errors::NotReversible(_) | Errors::CardAlreadyRevealed => { StatusCode::CONFLICT }
+            Errors::InternalError(_) => { StatusCode::INTERNAL_SERVER_ERROR }
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            Errors::Unauthorized(message) => { message.clone() }
+            Errors::InvalidCredentials => { "merchant id or secret is not valid".to_string() }
+            Errors::MerchantNotFound => { "merchant does not exist".to_string() }
+            Errors::AccountNotFound => { "account does not exist".to_string() }
+            Errors::CustomerNotFound => { "customer does not exist".to_string() }
+            Errors::CardNotFound => { "card does not exist".to_string() }
+            Errors::CardProgramNotFound => { "card program does not exist".to_string() }
+            Errors::TransactionNotFound => { "transaction does not exist".to_string() }
+            Errors::WebhookEndpointNotFound => { "webhook endpoint does not exist".to_string() }
+            Errors::WebhookEventNotFound => { "webhook event does not exist".to_string() }
+            Errors::InsufficientFunds => { "source account does not have enough funds".to_string() }
+            Errors::CardNotActive => { "card is frozen or terminated".to_string() }
+            Errors::CustomerNotActive => { "customer is deactivated".to_string() }
+            Errors::CustomerNotVerified => { "customer hasn't passed identity verification".to_string() }
+            Errors::CardAlreadyRevealed => { "card details can only be revealed once".to_string() }
+            Errors::CurrencyMismatch => {
+                "source account currency doesn't match destination account currency".to_string()
+            }
+            Errors::InvalidFields(errors) => {
+                let fields: Vec<&str> = errors.iter().map(|error| error.field).collect();
+                format!("fields are not valid: {}", fields.join(", "))
+            }
+            Errors::InvalidAmount(message) | Errors::InvalidLimit(message) | Errors::InvalidFilter(message)
+            | Errors::IdempotencyConflict(message)
+            | Errors::InvalidStatusTransition(message) | Errors::NotReversible(message)
+            | Errors::LimitExceeded(message) | Errors::CardRestricted(message) | Errors::AuthorizationDeclined(message)
+            | Errors::InvalidMerchantData(message) | Errors::InvalidClearingFile(message)
+            | Errors::InvalidDecisionConfig(message) | Errors::InvalidWebhookEndpoint(message)
+            | Errors::InvalidKycDocument(message) => { message.clone() }
+            Errors::RefundExceedsOriginal => { "refund amount exceeds what is left of the original transaction".to_string() }
+            Errors::CaptureExceedsAuthorization => { "capture amount exceeds what is left of the authorization".to_string() }
+            Errors::InternalError(_) => { "internal error".to_string() }
+        }
+    }
+}
+
+// RFC 7807 problem details, with the stable error code as an extension member
+#[derive(Serialize)]
+pub struct Problem<'a> {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    pub errors: &'a [FieldError],
+}
+
+pub fn reply<T: Serialize>(result: Result<T, Errors>) -> Result<Response, Rejection> {
+    match result {
+        Ok(body) => { Ok(json(&body).into_response()) }
+        Err(err) => { Ok(problem_reply(&err)) }
+    }
+}
+
+pub fn problem_reply(err: &Errors) -> Response {
+    if let Errors::InternalError(message) = err {
+        error!("internal error: {}", message);
+    }
+    let errors = match err {
+        Errors::InvalidFields(errors) => { errors.as_slice() }
+        _ => { &[] }
+    };
+    problem_with_errors(err.status(), err.code(), err.detail(), errors)
+}
+
+fn problem(status: StatusCode, code: &'static str, detail: String) -> Response {
+    problem_with_errors(status, code, detail, &[])
+}
+
+fn problem_with_errors(status: StatusCode, code: &'static str, detail: String, errors: &[FieldError]) -> Response {
+    let mut res = with_status(json(&Problem {
+        problem_type: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error").to_string(),
+        status: status.as_u16(),
+        detail,
+        code,
+        errors,
+    }), status).into_response();
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+    res
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    if let Some(e) = err.find::<Errors>() {
+        return Ok(problem_reply(e));
+    }
+    if err.is_not_found() {
+        return Ok(problem(StatusCode::NOT_FOUND, "not_found", "resource does not exist".to_string()));
+    }
+    if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        return Ok(problem(StatusCode::BAD_REQUEST, "invalid_body", e.to_string()));
+    }
+    if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        return Ok(problem(StatusCode::BAD_REQUEST, "invalid_query", e.to_string()));
+    }
+    if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        return Ok(problem(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type",
+                          "request body must be application/json".to_string()));
+    }
+    if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        return Ok(problem(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method is not allowed".to_string()));
+    }
+    warn!("unhandled rejection: {:?}", err);
+    Ok(problem(StatusCode::BAD_REQUEST, "bad_request", "request is not valid".to_string()))
+}
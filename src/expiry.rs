@@ -0,0 +1,34 @@
+use std::env;
+use std::time::Duration;
+use crate::db::{DBConn, DBPool, get_db_conn};
+use crate::error::Errors;
+use crate::transaction;
+use crate::transaction::TransactionStatus;
+
+const EXPIRY_INTERVAL_VAR: &str = "EXPIRY_INTERVAL";
+const DEFAULT_EXPIRY_INTERVAL: u64 = 60;
+
+pub async fn run(pool: DBPool) {
+    let period = env::var(EXPIRY_INTERVAL_VAR).ok().and_then(|val| val.parse().ok())
+        .unwrap_or(DEFAULT_EXPIRY_INTERVAL);
+    let mut interval = tokio::time::interval(Duration::from_secs(period));
+    loop {
+        interval.tick().await;
+        let mut conn = get_db_conn(&pool).await;
+        match expire(&mut conn).await {
+            Ok(0) => {}
+            Ok(count) => { info!("{} open transactions were expired", count) }
+            Err(e) => { error!("transaction expiry failed: {:?}", e) }
+        }
+    }
+}
+
+pub async fn expire(conn: &mut DBConn) -> Result<usize, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let ids = transaction::find_expired(&tx).await?;
+    for id in ids.iter() {
+        transaction::set_status(&tx, *id, TransactionStatus::Expired).await?;
+    }
+    transaction::commit(tx).await?;
+    Ok(ids.len())
+}
@@ -0,0 +1,45 @@
+use serde::Serialize;
+use tokio_postgres::GenericClient;
+use tokio_postgres::error::SqlState;
+use crate::token::sha256_hash;
+use crate::error::Errors;
+use crate::error::Errors::{IdempotencyConflict, InternalError};
+
+pub fn request_hash<T: Serialize>(req: &T) -> String {
+    sha256_hash(&serde_json::to_string(req).unwrap())
+}
+
+pub async fn find<C: GenericClient>(conn: &C, merch_id: i32, scope: &str, key: &str,
+                                    request_hash: &str) -> Result<Option<i32>, Errors> {
+    match conn.query("select resource_id, request_hash from idempotency_key where merch_id=$1 and scope=$2 and key=$3",
+                     &[&merch_id, &scope, &key]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Ok(None) }
+        Some(row) => {
+            let saved_hash: String = row.get("request_hash");
+            if saved_hash != request_hash {
+                return Err(IdempotencyConflict("Idempotency-Key was already used with different parameters".to_string()));
+            }
+            info!("{} request with Idempotency-Key: {} was replayed", scope, key);
+            Ok(Some(row.get("resource_id")))
+        }
+    }
+}
+
+// a parallel request with the same key that got here first makes this one fail, the caller's transaction is rolled
+// back and a retry replays the request that won
+pub async fn save<C: GenericClient>(conn: &C, merch_id: i32, scope: &str, key: &str, request_hash: &str,
+                                    resource_id: i32) -> Result<(), Errors> {
+    conn.execute("insert into idempotency_key (id, merch_id, scope, key, request_hash, resource_id, created) \
+     values (default, $1, $2, $3, $4, $5, now())", &[&merch_id, &scope, &key, &request_hash, &resource_id]).await
+        .map_err(|e| {
+            if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                IdempotencyConflict("request with this Idempotency-Key is already being processed".to_string())
+            } else {
+                InternalError(e.to_string())
+            }
+        })?;
+    Ok(())
+}
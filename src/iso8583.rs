@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use chrono::prelude::*;
+use serde::{Serialize, Deserialize};
+use warp::Rejection;
+use warp::reply::{Response, json};
+use warp::Reply;
+use crate::card;
+use crate::db::{DBConn, DBPool, get_db_conn};
+use crate::error::Errors;
+use crate::pan;
+use crate::token::AuthMerchant;
+use crate::transaction;
+use crate::transaction::{MerchantData, Order};
+
+// a JSON rendering of ISO 8583 messages: the MTI plus data elements keyed by field number, e.g.
+// {"mti": "0100", "fields": {"2": "<pan>", "4": "000000001250", "14": "2910", "18": "5541", "37": "000000000001",
+//  "43": "SHELL 123                NEW YORK     US"}}
+// field 43 follows the 40 character name/city/country layout, a 0400 refers to the original message by its field 37
+const PAN: u16 = 2;
+const AMOUNT: u16 = 4;
+const EXPIRY: u16 = 14;
+const MCC: u16 = 18;
+const RRN: u16 = 37;
+const AUTH_CODE: u16 = 38;
+const RESPONSE_CODE: u16 = 39;
+const CARD_ACCEPTOR: u16 = 43;
+const ECHOED_FIELDS: [u16; 8] = [3, 4, 7, 11, 12, 37, 41, 49];
+
+const APPROVED: &str = "00";
+const DO_NOT_HONOR: &str = "05";
+const INVALID_TRANSACTION: &str = "12";
+const INVALID_AMOUNT: &str = "13";
+const INVALID_CARD: &str = "14";
+const RECORD_NOT_FOUND: &str = "25";
+const FORMAT_ERROR: &str = "30";
+const INSUFFICIENT_FUNDS: &str = "51";
+const EXPIRED_CARD: &str = "54";
+const EXCEEDS_LIMIT: &str = "61";
+const RESTRICTED_CARD: &str = "62";
+const DUPLICATE: &str = "94";
+const SYSTEM_ERROR: &str = "96";
+
+#[derive(Serialize, Deserialize)]
+pub struct IsoMessage {
+    pub mti: String,
+    pub fields: BTreeMap<u16, String>,
+}
+
+pub async fn message_handler(pool: DBPool, merchant: AuthMerchant, msg: IsoMessage) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    Ok(json(&process(&mut conn, msg, merchant.id).await).into_response())
+}
+
+pub async fn process(conn: &mut DBConn, msg: IsoMessage, merch_id: i32) -> IsoMessage {
+    let result = match msg.mti.as_str() {
+        "0100" => { authorize(conn, &msg, merch_id, false).await.map(Some) }
+        "0200" => { authorize(conn, &msg, merch_id, true).await.map(Some) }
+        "0400" => { reverse(conn, &msg, merch_id).await.map(|_| None) }
+        _ => { Err(FORMAT_ERROR) }
+    };
+
+    let mut fields: BTreeMap<u16, String> = ECHOED_FIELDS.iter()
+        .filter_map(|field| msg.fields.get(field).map(|val| (*field, val.clone()))).collect();
+    if let Some(pan) = msg.fields.get(&PAN) {
+        fields.insert(PAN, pan::mask(pan));
+    }
+    match result {
+        Ok(auth_code) => {
+            fields.insert(RESPONSE_CODE, APPROVED.to_string());
+            if let Some(auth_code) = auth_code {
+                fields.insert(AUTH_CODE, auth_code);
+            }
+        }
+        Err(code) => { fields.insert(RESPONSE_CODE, code.to_string()); }
+    }
+    info!("iso message {} answered with response code: {}", msg.mti, fields[&RESPONSE_CODE]);
+    IsoMessage {
+        mti: response_mti(&msg.mti),
+        fields,
+    }
+}
+
+// 0100 places a hold, 0200 posts the spend right away
+async fn authorize(conn: &mut DBConn, msg: &IsoMessage, merch_id: i32, financial: bool) -> Result<String, &'static str> {
+    let pan = field(msg, PAN)?;
+    let amount: i32 = field(msg, AMOUNT)?.parse().ok().filter(|amount| *amount > 0).ok_or(INVALID_AMOUNT)?;
+    let acceptor = field(msg, CARD_ACCEPTOR)?;
+    if acceptor.len() < 3 || !acceptor.is_char_boundary(acceptor.len() - 2) {
+        return Err(FORMAT_ERROR);
+    }
+    let (descriptor, country) = acceptor.split_at(acceptor.len() - 2);
+
+    let card = card::get_by_pan(&**conn, pan, merch_id).await.map_err(|e| response_code(&e))?;
+    let now = Utc::now();
+    if field(msg, EXPIRY)? != format!("{:02}{:02}", card.exp_year % 100, card.exp_month)
+        || (now.year(), now.month() as i32) > (card.exp_year, card.exp_month) {
+        return Err(EXPIRED_CARD);
+    }
+
+    let req = card::SpendRequest {
+        card_id: card.id,
+        amount,
+        order_id: format!("iso-{}", field(msg, RRN)?),
+        merchant: MerchantData {
+            descriptor: descriptor.trim().to_string(),
+            mcc: field(msg, MCC)?.to_string(),
+            country: country.to_string(),
+        },
+    };
+    let order = Order::system(merch_id, req.order_id.clone(), &req);
+    let trans_id = if financial {
+        card::withdraw_order(conn, req, order, merch_id).await
+    } else {
+        card::authorize_order(conn, req, order, merch_id).await
+    }.map_err(|e| response_code(&e))?;
+    let spend = transaction::get_by_id(&**conn, trans_id, merch_id).await.map_err(|e| response_code(&e))?;
+    spend.auth_code.ok_or(SYSTEM_ERROR)
+}
+
+// holds are voided and posted spends reversed, reversing twice is approved again
+async fn reverse(conn: &mut DBConn, msg: &IsoMessage, merch_id: i32) -> Result<(), &'static str> {
+    let rrn = field(msg, RRN)?;
+    let original = transaction::get_by_system_ref(&**conn, &format!("iso-{}", rrn), merch_id).await
+        .map_err(|e| response_code(&e))?;
+    match (original.trans_type.as_str(), original.status.as_str()) {
+        ("card_authorization", "authorized") => {
+            card::void(conn, original.id, merch_id).await.map_err(|e| response_code(&e))
+        }
+        ("virtual_card_withdraw", "completed") => {
+            let order = Order::system(merch_id, format!("iso-reversal-{}", rrn), &original.id);
+            transaction::reverse_order(conn, original.id, order, merch_id).await.map(|_| ()).map_err(|e| response_code(&e))
+        }
+        ("card_authorization", "voided") | ("virtual_card_withdraw", "reversed") => { Ok(()) }
+        _ => { Err(INVALID_TRANSACTION) }
+    }
+}
+
+fn field(msg: &IsoMessage, field: u16) -> Result<&str, &'static str> {
+    msg.fields.get(&field).map(|val| val.as_str()).ok_or(FORMAT_ERROR)
+}
+
+fn response_mti(mti: &str) -> String {
+    mti.chars().enumerate().map(|(i, c)| {
+        match (i, c.to_digit(10)) {
+            (2, Some(digit)) => { char::from_digit((digit + 1) % 10, 10).unwrap() }
+            _ => { c }
+        }
+    }).collect()
+}
+
+fn response_code(err: &Errors) -> &'static str {
+    match err {
+        Errors::InsufficientFunds => { INSUFFICIENT_FUNDS }
+        Errors::CardNotActive | Errors::CardRestricted(_) => { RESTRICTED_CARD }
+        Errors::LimitExceeded(_) => { EXCEEDS_LIMIT }
+        Errors::AuthorizationDeclined(_) => { DO_NOT_HONOR }
+        Errors::CardNotFound => { INVALID_CARD }
+        Errors::InvalidAmount(_) => { INVALID_AMOUNT }
+        Errors::InvalidMerchantData(_) => { FORMAT_ERROR }
+        Errors::IdempotencyConflict(_) => { DUPLICATE }
+        Errors::TransactionNotFound => { RECORD_NOT_FOUND }
+        _ => {
+            error!("iso message failed: {:?}", err);
+            SYSTEM_ERROR
+        }
+    }
+}
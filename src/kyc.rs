@@ -0,0 +1,423 @@
+use std::env;
+use std::time::Duration;
+use chrono::prelude::*;
+use serde::{Serialize, Deserialize};
+use tokio_postgres::{GenericClient, Row};
+use warp::reply::Response;
+use warp::Rejection;
+use crate::customer;
+use crate::customer::Customer;
+use crate::db::{DBConn, DBPool, get_db_conn};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{CustomerNotActive, CustomerNotVerified, InternalError, InvalidKycDocument,
+                           InvalidStatusTransition};
+use crate::token::AuthMerchant;
+use crate::{transaction, webhook};
+
+// merchants upload metadata of the documents they collected, the files themselves stay in the merchant's storage.
+// submitting hands the customer and its documents to the verification provider, customers start as unverified
+// and can be submitted again after a rejection. pending customers are polled until the provider decides, a submission
+// the provider couldn't take goes back to unverified so it can be retried
+const POLL_INTERVAL_VAR: &str = "KYC_POLL_INTERVAL";
+const DEFAULT_POLL_INTERVAL: u64 = 60;
+const MAX_FILE_NAME_LENGTH: usize = 255;
+const MAX_DOCUMENT_SIZE: i64 = 10 * 1024 * 1024;
+const CONTENT_TYPES: [&str; 3] = ["application/pdf", "image/jpeg", "image/png"];
+
+// swap in another Provider implementation here to verify with a real provider
+static PROVIDER: StubProvider = StubProvider;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum KycStatus {
+    Unverified,
+    Pending,
+    Verified,
+    Rejected,
+}
+
+impl KycStatus {
+    pub fn to_db_val(self) -> &'static str {
+        match self {
+            KycStatus::Unverified => { "unverified" }
+            KycStatus::Pending => { "pending" }
+            KycStatus::Verified => { "verified" }
+            KycStatus::Rejected => { "rejected" }
+        }
+    }
+
+    pub fn from_db_val(val: &str) -> Option<KycStatus> {
+        match val {
+            "unverified" => { Some(KycStatus::Unverified) }
+            "pending" => { Some(KycStatus::Pending) }
+            "verified" => { Some(KycStatus::Verified) }
+            "rejected" => { Some(KycStatus::Rejected) }
+            _ => { None }
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum DocumentType {
+    Passport,
+    IdCard,
+    DrivingLicense,
+    ProofOfAddress,
+}
+
+impl DocumentType {
+    fn to_db_val(&self) -> &'static str {
+        match self {
+            DocumentType::Passport => { "passport" }
+            DocumentType::IdCard => { "id_card" }
+            DocumentType::DrivingLicense => { "driving_license" }
+            DocumentType::ProofOfAddress => { "proof_of_address" }
+        }
+    }
+
+    fn from_db_val(val: &str) -> Option<DocumentType> {
+        match val {
+            "passport" => { Some(DocumentType::Passport) }
+            "id_card" => { Some(DocumentType::IdCard) }
+            "driving_license" => { Some(DocumentType::DrivingLicense) }
+            "proof_of_address" => { Some(DocumentType::ProofOfAddress) }
+            _ => { None }
+        }
+    }
+
+    fn proves_identity(&self) -> bool {
+        *self != DocumentType::ProofOfAddress
+    }
+}
+
+pub enum Outcome {
+    Verified,
+    Rejected(String),
+    // the provider needs more time, e.g. for a manual review
+    Pending,
+}
+
+pub trait Provider {
+    fn name(&self) -> &'static str;
+
+    async fn verify(&self, customer: &Customer, documents: &[Document]) -> Result<Outcome, String>;
+
+    // asks again about a customer that was left pending
+    async fn poll(&self, customer: &Customer, documents: &[Document]) -> Result<Outcome, String>;
+}
+
+// decides on the last name so every outcome can be produced locally:
+// "Rejected" is rejected, "Review" stays pending until it's polled, "Unavailable" fails like an unreachable provider
+// and everybody else is verified
+pub struct StubProvider;
+
+impl Provider for StubProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn verify(&self, customer: &Customer, documents: &[Document]) -> Result<Outcome, String> {
+        if !documents.iter().any(|document| {
+            DocumentType::from_db_val(&document.doc_type).is_some_and(|doc_type| doc_type.proves_identity())
+        }) {
+            return Ok(Outcome::Rejected("no identity document was provided".to_string()));
+        }
+        match customer.last_name.to_ascii_lowercase().as_str() {
+            "rejected" => { Ok(Outcome::Rejected("identity couldn't be confirmed".to_string())) }
+            "review" => { Ok(Outcome::Pending) }
+            "unavailable" => { Err("provider is unavailable".to_string()) }
+            _ => { Ok(Outcome::Verified) }
+        }
+    }
+
+    async fn poll(&self, customer: &Customer, documents: &[Document]) -> Result<Outcome, String> {
+        match self.verify(customer, documents).await? {
+            Outcome::Pending => { Ok(Outcome::Verified) }
+            outcome => { Ok(outcome) }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DocumentRequest {
+    #[serde(rename = "type")]
+    pub doc_type: String,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: Option<String>,
+}
+
+impl DocumentRequest {
+    fn validate(&self) -> Result<(), Errors> {
+        if DocumentType::from_db_val(&self.doc_type).is_none() {
+            return Err(InvalidKycDocument(format!("type must be one of {}, {}, {} or {}",
+                                                  DocumentType::Passport.to_db_val(), DocumentType::IdCard.to_db_val(),
+                                                  DocumentType::DrivingLicense.to_db_val(),
+                                                  DocumentType::ProofOfAddress.to_db_val())));
+        }
+        if self.file_name.trim().is_empty() || self.file_name.chars().count() > MAX_FILE_NAME_LENGTH {
+            return Err(InvalidKycDocument(format!("fileName must be 1 to {} characters", MAX_FILE_NAME_LENGTH)));
+        }
+        if !CONTENT_TYPES.contains(&self.content_type.as_str()) {
+            return Err(InvalidKycDocument(format!("contentType must be one of {}", CONTENT_TYPES.join(", "))));
+        }
+        if !(1..=MAX_DOCUMENT_SIZE).contains(&self.size) {
+            return Err(InvalidKycDocument(format!("size must be between 1 and {} bytes", MAX_DOCUMENT_SIZE)));
+        }
+        if let Some(sha256) = &self.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(InvalidKycDocument("sha256 must be 64 hex characters".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Document {
+    pub id: i32,
+    #[serde(rename = "type")]
+    pub doc_type: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl Document {
+    fn from_row(row: &Row) -> Document {
+        Document {
+            id: row.get("id"),
+            doc_type: row.get("type"),
+            file_name: row.get("file_name"),
+            content_type: row.get("content_type"),
+            size: row.get("size"),
+            sha256: row.get("sha256"),
+            created: row.get("created"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct KycResponse {
+    pub customer_id: i32,
+    pub status: String,
+    pub reason: Option<String>,
+    pub documents: Vec<Document>,
+}
+
+#[derive(Serialize)]
+struct StatusEvent<'a> {
+    customer_id: i32,
+    status: &'static str,
+    reason: Option<&'a str>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(rename = "requireVerification")]
+    pub require_verification: bool,
+}
+
+pub async fn get_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get(&*conn, id, merchant.id).await)
+}
+
+pub async fn document_handler(id: i32, pool: DBPool, merchant: AuthMerchant,
+                              req: DocumentRequest) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(add_document(&mut conn, id, merchant.id, req).await)
+}
+
+pub async fn submit_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(submit(&mut conn, id, merchant.id).await)
+}
+
+pub async fn policy_handler(pool: DBPool, merchant: AuthMerchant, req: Policy) -> Result<Response, Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(set_policy(&*conn, merchant.id, req).await)
+}
+
+pub async fn get<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<KycResponse, Errors> {
+    let customer = customer::get_by_id(conn, id, merch_id).await?;
+    Ok(KycResponse {
+        customer_id: id,
+        status: customer.kyc_status,
+        reason: customer.kyc_reason,
+        documents: get_documents(conn, id).await?,
+    })
+}
+
+pub async fn add_document(conn: &mut DBConn, id: i32, merch_id: i32, req: DocumentRequest) -> Result<Document, Errors> {
+    req.validate()?;
+    let tx = transaction::begin(conn).await?;
+    let customer = customer::lock_by_id(&tx, id, merch_id).await?;
+    if !customer.active {
+        return Err(CustomerNotActive);
+    }
+    match KycStatus::from_db_val(&customer.kyc_status) {
+        Some(KycStatus::Unverified) | Some(KycStatus::Rejected) => {}
+        _ => {
+            return Err(InvalidStatusTransition(format!("documents can't be added to a {} customer",
+                                                       customer.kyc_status)));
+        }
+    }
+    let document = tx.query("insert into kyc_document (id, cust_id, type, file_name, content_type, size, sha256, created) \
+     values (default, $1, $2, $3, $4, $5, $6, now()) returning *",
+                            &[&id, &req.doc_type, &req.file_name.trim(), &req.content_type, &req.size,
+                                &req.sha256.map(|sha256| sha256.to_ascii_lowercase())]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().map(Document::from_row).unwrap();
+    transaction::commit(tx).await?;
+    info!("{} document: {} was added to customer: {}", document.doc_type, document.id, id);
+    Ok(document)
+}
+
+// the customer is marked pending before the provider is asked, so it can't be submitted twice at the same time
+pub async fn submit(conn: &mut DBConn, id: i32, merch_id: i32) -> Result<KycResponse, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let customer = customer::lock_by_id(&tx, id, merch_id).await?;
+    if !customer.active {
+        return Err(CustomerNotActive);
+    }
+    match KycStatus::from_db_val(&customer.kyc_status) {
+        Some(KycStatus::Unverified) | Some(KycStatus::Rejected) => {}
+        _ => {
+            return Err(InvalidStatusTransition(format!("a {} customer can't be submitted for verification",
+                                                       customer.kyc_status)));
+        }
+    }
+    let documents = get_documents(&tx, id).await?;
+    if documents.is_empty() {
+        return Err(InvalidKycDocument("at least one document must be added before submitting".to_string()));
+    }
+    set_status(&tx, id, merch_id, KycStatus::Pending, None).await?;
+    transaction::commit(tx).await?;
+
+    let outcome = PROVIDER.verify(&customer, &documents).await;
+    let response = resolve(conn, id, merch_id, outcome).await?;
+    info!("customer: {} was submitted to {} verification and is {}", id, PROVIDER.name(), response.status);
+    Ok(response)
+}
+
+pub async fn run(pool: DBPool) {
+    let period = env::var(POLL_INTERVAL_VAR).ok().and_then(|val| val.parse().ok())
+        .unwrap_or(DEFAULT_POLL_INTERVAL);
+    let mut interval = tokio::time::interval(Duration::from_secs(period));
+    loop {
+        interval.tick().await;
+        let mut conn = get_db_conn(&pool).await;
+        match poll_pending(&mut conn).await {
+            Ok(0) => {}
+            Ok(count) => { info!("{} pending verifications were decided", count) }
+            Err(e) => { error!("polling pending verifications failed: {:?}", e) }
+        }
+    }
+}
+
+// the provider is asked without holding the customer, a customer that stays pending or can't be polled is asked
+// again on the next run
+pub async fn poll_pending(conn: &mut DBConn) -> Result<usize, Errors> {
+    let rows = conn.query("select id, merch_id from customer where kyc_status = $1 order by id",
+                          &[&KycStatus::Pending.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let mut decided = 0;
+    for row in rows.iter() {
+        let (id, merch_id): (i32, i32) = (row.get("id"), row.get("merch_id"));
+        let customer = customer::get_by_id(&**conn, id, merch_id).await?;
+        let documents = get_documents(&**conn, id).await?;
+        match PROVIDER.poll(&customer, &documents).await {
+            Ok(Outcome::Pending) => {}
+            Ok(outcome) => {
+                let response = resolve(conn, id, merch_id, Ok(outcome)).await?;
+                info!("pending customer: {} was polled from {} verification and is {}", id, PROVIDER.name(),
+                      response.status);
+                decided += 1;
+            }
+            Err(e) => { warn!("{} verification of customer: {} couldn't be polled: {}", PROVIDER.name(), id, e); }
+        }
+    }
+    Ok(decided)
+}
+
+// the answer only counts while the customer is still pending, its identity may have changed in the meantime
+async fn resolve(conn: &mut DBConn, id: i32, merch_id: i32,
+                 outcome: Result<Outcome, String>) -> Result<KycResponse, Errors> {
+    let tx = transaction::begin(conn).await?;
+    let customer = customer::lock_by_id(&tx, id, merch_id).await?;
+    if KycStatus::from_db_val(&customer.kyc_status) == Some(KycStatus::Pending) {
+        match outcome {
+            Ok(Outcome::Verified) => { set_status(&tx, id, merch_id, KycStatus::Verified, None).await?; }
+            Ok(Outcome::Rejected(reason)) => {
+                set_status(&tx, id, merch_id, KycStatus::Rejected, Some(&reason)).await?;
+            }
+            Ok(Outcome::Pending) => {}
+            Err(e) => {
+                warn!("{} verification of customer: {} failed: {}", PROVIDER.name(), id, e);
+                set_status(&tx, id, merch_id, KycStatus::Unverified,
+                           Some("verification provider is unavailable, submit again")).await?;
+            }
+        }
+    }
+    let response = get(&tx, id, merch_id).await?;
+    transaction::commit(tx).await?;
+    Ok(response)
+}
+
+// a changed identity invalidates the verification, the customer has to be submitted again
+pub async fn reset<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<(), Errors> {
+    set_status(conn, id, merch_id, KycStatus::Unverified, Some("identity details were changed")).await
+}
+
+async fn set_status<C: GenericClient>(conn: &C, id: i32, merch_id: i32, status: KycStatus,
+                                      reason: Option<&str>) -> Result<(), Errors> {
+    conn.execute("update customer set kyc_status = $1, kyc_reason = $2 where id = $3",
+                 &[&status.to_db_val(), &reason, &id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    webhook::publish(conn, merch_id, "customer.kyc_status_changed", &StatusEvent {
+        customer_id: id,
+        status: status.to_db_val(),
+        reason,
+    }).await
+}
+
+async fn get_documents<C: GenericClient>(conn: &C, cust_id: i32) -> Result<Vec<Document>, Errors> {
+    Ok(conn.query("select * from kyc_document where cust_id = $1 order by id", &[&cust_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.iter().map(Document::from_row).collect())
+}
+
+pub async fn set_policy<C: GenericClient>(conn: &C, merch_id: i32, policy: Policy) -> Result<Policy, Errors> {
+    conn.execute("insert into kyc_policy (merch_id, require_verification, updated) values ($1, $2, now()) \
+     on conflict (merch_id) do update set require_verification = $2, updated = now()",
+                 &[&merch_id, &policy.require_verification]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    info!("merchant: {} {} verified customers for cards", merch_id,
+          if policy.require_verification { "requires" } else { "doesn't require" });
+    Ok(policy)
+}
+
+// merchants without a policy issue cards to unverified customers, like before verification existed
+pub async fn check<C: GenericClient>(conn: &C, customer: &Customer, merch_id: i32) -> Result<(), Errors> {
+    let required = conn.query("select require_verification from kyc_policy where merch_id = $1", &[&merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().is_some_and(|row| row.get("require_verification"));
+    if required && KycStatus::from_db_val(&customer.kyc_status) != Some(KycStatus::Verified) {
+        return Err(CustomerNotVerified);
+    }
+    Ok(())
+}
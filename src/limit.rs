@@ -0,0 +1,117 @@
+use chrono::prelude::*;
+use serde::{Serialize, Deserialize};
+use tokio_postgres::{GenericClient, Row, Transaction};
+use crate::error::Errors;
+use crate::error::Errors::{InternalError, InvalidLimit, LimitExceeded};
+use crate::transaction;
+
+#[derive(Serialize, Deserialize)]
+pub struct Limits {
+    #[serde(rename = "perTransaction")]
+    pub per_transaction: Option<i64>,
+    pub daily: Option<i64>,
+    pub weekly: Option<i64>,
+    pub monthly: Option<i64>,
+    pub lifetime: Option<i64>,
+    #[serde(rename = "dailyCount")]
+    pub daily_count: Option<i64>,
+    #[serde(rename = "weeklyCount")]
+    pub weekly_count: Option<i64>,
+    #[serde(rename = "monthlyCount")]
+    pub monthly_count: Option<i64>,
+    #[serde(rename = "allowedFrom")]
+    pub allowed_from: Option<NaiveTime>,
+    #[serde(rename = "allowedTo")]
+    pub allowed_to: Option<NaiveTime>,
+}
+
+impl Limits {
+    fn from_row(row: &Row) -> Limits {
+        Limits {
+            per_transaction: row.get("per_transaction"),
+            daily: row.get("daily"),
+            weekly: row.get("weekly"),
+            monthly: row.get("monthly"),
+            lifetime: row.get("lifetime"),
+            daily_count: row.get("daily_count"),
+            weekly_count: row.get("weekly_count"),
+            monthly_count: row.get("monthly_count"),
+            allowed_from: row.get("allowed_from"),
+            allowed_to: row.get("allowed_to"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Errors> {
+        for (name, limit) in [("perTransaction", self.per_transaction), ("daily", self.daily), ("weekly", self.weekly),
+            ("monthly", self.monthly), ("lifetime", self.lifetime), ("dailyCount", self.daily_count),
+            ("weeklyCount", self.weekly_count), ("monthlyCount", self.monthly_count)] {
+            if limit.is_some_and(|limit| limit <= 0) {
+                return Err(InvalidLimit(format!("{} must be positive", name)));
+            }
+        }
+        if self.allowed_from.is_some() != self.allowed_to.is_some() {
+            return Err(InvalidLimit("allowedFrom and allowedTo must be set together".to_string()));
+        }
+        Ok(())
+    }
+
+    // a window with from after to wraps around midnight
+    fn is_allowed_at(&self, time: NaiveTime) -> bool {
+        match (self.allowed_from, self.allowed_to) {
+            (Some(from), Some(to)) if from <= to => { from <= time && time < to }
+            (Some(from), Some(to)) => { time >= from || time < to }
+            _ => { true }
+        }
+    }
+}
+
+pub async fn save<C: GenericClient>(conn: &C, card_id: i32, limits: &Limits) -> Result<(), Errors> {
+    limits.validate()?;
+    conn.execute("insert into card_limit (card_id, per_transaction, daily, weekly, monthly, lifetime, daily_count, \
+     weekly_count, monthly_count, allowed_from, allowed_to, updated) \
+     values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, now()) \
+     on conflict (card_id) do update set per_transaction = $2, daily = $3, weekly = $4, monthly = $5, lifetime = $6, \
+     daily_count = $7, weekly_count = $8, monthly_count = $9, allowed_from = $10, allowed_to = $11, updated = now()",
+                 &[&card_id, &limits.per_transaction, &limits.daily, &limits.weekly, &limits.monthly, &limits.lifetime,
+                     &limits.daily_count, &limits.weekly_count, &limits.monthly_count, &limits.allowed_from,
+                     &limits.allowed_to]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    info!("limits of card: {} were updated", card_id);
+    Ok(())
+}
+
+// the limit row stays locked until the spend is committed, so parallel spends on a card can't both fit the same headroom
+pub async fn check(conn: &Transaction<'_>, card_id: i32, amount: i32) -> Result<(), Errors> {
+    let limits = match conn.query("select * from card_limit where card_id = $1 for update", &[&card_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Ok(()); }
+        Some(row) => { Limits::from_row(row) }
+    };
+    let amount = amount as i64;
+
+    if limits.per_transaction.is_some_and(|limit| amount > limit) {
+        return Err(LimitExceeded("amount is over the per transaction limit".to_string()));
+    }
+    if !limits.is_allowed_at(Utc::now().time()) {
+        return Err(LimitExceeded("card can't be used at this time of day".to_string()));
+    }
+
+    let spend = transaction::get_card_spend(conn, card_id).await?;
+    for (window, limit, spent, count_limit, count) in [
+        ("daily", limits.daily, spend.daily, limits.daily_count, spend.daily_count),
+        ("weekly", limits.weekly, spend.weekly, limits.weekly_count, spend.weekly_count),
+        ("monthly", limits.monthly, spend.monthly, limits.monthly_count, spend.monthly_count),
+        ("lifetime", limits.lifetime, spend.lifetime, None, 0)] {
+        if limit.is_some_and(|limit| spent + amount > limit) {
+            return Err(LimitExceeded(format!("amount is over the {} spend limit", window)));
+        }
+        if count_limit.is_some_and(|limit| count + 1 > limit) {
+            return Err(LimitExceeded(format!("card reached its {} transaction count", window)));
+        }
+    }
+    Ok(())
+}
@@ -4,12 +4,27 @@ mod merchant;
 mod transaction;
 mod account;
 mod card;
+mod pan;
+mod limit;
+mod restriction;
 mod customer;
+mod idempotency;
+mod error;
+mod reconciliation;
+mod expiry;
+mod vault;
+mod iso8583;
+mod clearing;
+mod decision;
+mod outbound;
+mod webhook;
+mod validation;
+mod kyc;
 
-use warp::Filter;
-use crate::db::{create_pool, DBPool};
+use warp::{Filter, Rejection};
+use crate::db::{create_pool, get_db_conn, DBPool};
+use crate::token::AuthMerchant;
 use std::convert::Infallible;
-use serde::{Serialize};
 
 extern crate pretty_env_logger;
 #[macro_use]
@@ -17,21 +32,15 @@ extern crate log;
 
 use std::env;
 
+const PORT_VAR: &str = "PORT";
+const DEFAULT_PORT: u16 = 8080;
+
 fn with_db(db_pool: DBPool) -> impl Filter<Extract=(DBPool, ), Error=Infallible> + Clone {
     warp::any().map(move || db_pool.clone())
 }
 
-pub enum Errors {
-    MerchantError(String),
-    AccountError(String),
-    CustomerError(String),
-    CardError(String),
-    TransactionError(String),
-}
-
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    pub error: String,
+fn with_merchant() -> impl Filter<Extract=(AuthMerchant, ), Error=Rejection> + Clone {
+    warp::header::optional::<String>("Authorization").and_then(token::validate_auth_header)
 }
 
 #[tokio::main]
@@ -41,35 +50,220 @@ async fn main() {
     let log = warp::log("myLog");
 
     let pool = create_pool().unwrap();
+    vault::init();
+
+    if let Some(command) = env::args().nth(1) {
+        let mut conn = get_db_conn(&pool).await;
+        match command.as_str() {
+            "rotate-keys" => { vault::rotate(&mut conn).await.unwrap(); }
+            "import-clearing" => {
+                let usage = "usage: import-clearing <merchant id> <file>";
+                let merch_id: i32 = env::args().nth(2).and_then(|id| id.parse().ok()).expect(usage);
+                let path = env::args().nth(3).expect(usage);
+                let report = clearing::import_file(&mut conn, &path, merch_id).await.unwrap();
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            }
+            _ => { error!("unknown command: {}", command); }
+        }
+        return;
+    }
+
+    tokio::spawn(reconciliation::run(pool.clone()));
+    tokio::spawn(expiry::run(pool.clone()));
+    tokio::spawn(webhook::run(pool.clone()));
+    tokio::spawn(kyc::run(pool.clone()));
 
     let token_route = warp::path!("api"/"token").and(warp::post())
         .and(with_db(pool.clone())).and(warp::body::json())
         .and_then(token::create_token_handler);
 
+    let refresh_token_route = warp::path!("api"/"token"/"refresh").and(warp::post())
+        .and(with_db(pool.clone())).and(warp::body::json())
+        .and_then(token::refresh_token_handler);
+
+    let revoke_token_route = warp::path!("api"/"token"/"revoke").and(warp::post())
+        .and(with_db(pool.clone())).and(warp::body::json())
+        .and_then(token::revoke_token_handler);
+
     let fund_route = warp::path!("api"/"account"/"fund").and(warp::post())
-        .and(with_db(pool.clone())).and(warp::header("Authorization"))
+        .and(with_db(pool.clone())).and(with_merchant())
         .and(warp::body::json()).and_then(transaction::fund_account_handler);
 
     let create_customer = warp::path!("api"/"customer").and(warp::post())
-        .and(with_db(pool.clone())).and(warp::header("Authorization"))
+        .and(with_db(pool.clone())).and(with_merchant()).and(warp::header::optional("Idempotency-Key"))
         .and(warp::body::json()).and_then(customer::create_handler);
 
+    let get_customer = warp::path!("api"/"customer"/i32).and(warp::get())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(customer::get_handler);
+
+    let update_customer = warp::path!("api"/"customer"/i32).and(warp::patch())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(customer::update_handler);
+
+    let search_customers = warp::path!("api"/"customers").and(warp::get())
+        .and(warp::query()).and(with_db(pool.clone())).and(with_merchant())
+        .and_then(customer::search_handler);
+
+    let deactivate_customer = warp::path!("api"/"customer"/i32/"deactivate").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(customer::deactivate_handler);
+
+    let customer_kyc = warp::path!("api"/"customer"/i32/"kyc").and(warp::get())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(kyc::get_handler);
+
+    let kyc_document = warp::path!("api"/"customer"/i32/"kyc"/"documents").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(kyc::document_handler);
+
+    let submit_kyc = warp::path!("api"/"customer"/i32/"kyc"/"submit").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(kyc::submit_handler);
+
     let create_card = warp::path!("api"/"card").and(warp::post())
-        .and(with_db(pool.clone())).and(warp::header("Authorization"))
+        .and(with_db(pool.clone())).and(with_merchant()).and(warp::header::optional("Idempotency-Key"))
         .and(warp::body::json()).and_then(card::create_virtual_handler);
 
     let deposit_card = warp::path!("api"/"card"/"deposit").and(warp::post())
-        .and(with_db(pool.clone())).and(warp::header("Authorization"))
+        .and(with_db(pool.clone())).and(with_merchant())
         .and(warp::body::json()).and_then(card::deposit_virtual_handler);
 
     let withdraw_card = warp::path!("api"/"card"/"withdraw").and(warp::post())
-        .and(with_db(pool.clone())).and(warp::header("Authorization"))
+        .and(with_db(pool.clone())).and(with_merchant())
         .and(warp::body::json()).and_then(card::withdraw_virtual_handler);
 
-    let routes = token_route.or(fund_route).or(create_customer)
-        .or(create_card).or(deposit_card).or(withdraw_card).with(log);
+    let authorize_card = warp::path!("api"/"card"/"authorize").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(card::authorize_virtual_handler);
+
+    let capture_card = warp::path!("api"/"card"/"authorization"/i32/"capture").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(card::capture_handler);
+
+    let void_card = warp::path!("api"/"card"/"authorization"/i32/"void").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::void_handler);
+
+    let freeze_card = warp::path!("api"/"card"/i32/"freeze").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::freeze_handler);
+
+    let unfreeze_card = warp::path!("api"/"card"/i32/"unfreeze").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::unfreeze_handler);
+
+    let terminate_card = warp::path!("api"/"card"/i32/"terminate").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::terminate_handler);
+
+    let card_limits = warp::path!("api"/"card"/i32/"limits").and(warp::put())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(card::limits_handler);
+
+    let card_restrictions = warp::path!("api"/"card"/i32/"restrictions").and(warp::put())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(card::restrictions_handler);
+
+    let reveal_card = warp::path!("api"/"card"/i32/"reveal").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::reveal_handler);
+
+    let account_balance = warp::path!("api"/"account"/i32/"balance").and(warp::get())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(account::balance_handler);
+
+    let card_balance = warp::path!("api"/"card"/i32/"balance").and(warp::get())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::balance_handler);
+
+    let account_history = warp::path!("api"/"account"/i32/"transactions").and(warp::get())
+        .and(warp::query()).and(with_db(pool.clone())).and(with_merchant())
+        .and_then(transaction::history_handler);
+
+    let card_history = warp::path!("api"/"card"/i32/"transactions").and(warp::get())
+        .and(warp::query()).and(with_db(pool.clone())).and(with_merchant())
+        .and_then(card::history_handler);
+
+    let get_transaction = warp::path!("api"/"transaction"/i32).and(warp::get())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(transaction::get_handler);
+
+    let find_transaction = warp::path!("api"/"transaction").and(warp::get())
+        .and(warp::query()).and(with_db(pool.clone())).and(with_merchant())
+        .and_then(transaction::get_by_order_handler);
+
+    let reverse_transaction = warp::path!("api"/"transaction"/i32/"reverse").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(transaction::reverse_handler);
+
+    let refund_transaction = warp::path!("api"/"transaction"/i32/"refund").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(transaction::refund_handler);
+
+    let iso_message = warp::path!("api"/"iso8583").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(iso8583::message_handler);
+
+    let import_clearing = warp::path!("api"/"clearing").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::content_length_limit(clearing::MAX_FILE_SIZE)).and(warp::body::bytes())
+        .and_then(clearing::import_handler);
+
+    let set_decision_webhook = warp::path!("api"/"merchant"/"authorization-webhook").and(warp::put())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(decision::config_handler);
+
+    let delete_decision_webhook = warp::path!("api"/"merchant"/"authorization-webhook").and(warp::delete())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(decision::delete_handler);
+
+    let kyc_policy = warp::path!("api"/"merchant"/"kyc").and(warp::put())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(kyc::policy_handler);
+
+    let register_webhook = warp::path!("api"/"webhook"/"endpoint").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and(warp::body::json()).and_then(webhook::register_handler);
+
+    let remove_webhook = warp::path!("api"/"webhook"/"endpoint"/i32).and(warp::delete())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(webhook::remove_handler);
+
+    let webhook_events = warp::path!("api"/"webhook"/"events").and(warp::get())
+        .and(warp::query()).and(with_db(pool.clone())).and(with_merchant())
+        .and_then(webhook::events_handler);
+
+    let redeliver_webhook = warp::path!("api"/"webhook"/"event"/i32/"redeliver").and(warp::post())
+        .and(with_db(pool.clone())).and(with_merchant())
+        .and_then(webhook::redeliver_handler);
+
+    // groups are boxed so the combined filter stays small enough for the type checker and the worker stacks
+    let customer_routes = create_customer
+        .or(get_customer).or(update_customer).or(search_customers).or(deactivate_customer)
+        .or(customer_kyc).or(kyc_document).or(submit_kyc)
+        .boxed();
+
+    let card_routes = create_card
+        .or(deposit_card).or(withdraw_card).or(authorize_card).or(capture_card).or(void_card)
+        .or(freeze_card).or(unfreeze_card).or(terminate_card).or(reveal_card).or(card_limits).or(card_restrictions)
+        .or(card_balance).or(card_history)
+        .boxed();
+
+    let merchant_routes = iso_message.or(import_clearing)
+        .or(set_decision_webhook).or(delete_decision_webhook).or(kyc_policy)
+        .or(register_webhook).or(remove_webhook).or(webhook_events).or(redeliver_webhook)
+        .boxed();
+
+    let routes = token_route.or(refresh_token_route).or(revoke_token_route)
+        .or(fund_route).or(customer_routes).or(card_routes)
+        .or(account_balance).or(account_history)
+        .or(get_transaction).or(find_transaction).or(reverse_transaction).or(refund_transaction)
+        .or(merchant_routes)
+        .recover(error::handle_rejection).with(log);
 
+    let port = env::var(PORT_VAR).ok().and_then(|val| val.parse().ok()).unwrap_or(DEFAULT_PORT);
     warp::serve(routes)
-        .run(([127, 0, 0, 1], 8080))
+        .run(([127, 0, 0, 1], port))
         .await;
 }
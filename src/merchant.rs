@@ -1,7 +1,8 @@
 use crate::db::DBConn;
-use crate::Errors::MerchantError;
-use crate::Errors;
+use crate::error::Errors;
+use crate::error::Errors::{InternalError, MerchantNotFound};
 
+#[allow(dead_code)]
 pub struct Merchant {
     pub id: i32,
     pub name: String,
@@ -11,10 +12,10 @@ pub struct Merchant {
 pub async fn get_merchant_by_id(conn: &DBConn, id: i32) -> Result<Merchant, Errors> {
     match conn.query("select name, secret from merchant where id=$1", &[&id]).await
         .map_err(|e| {
-            MerchantError(e.to_string())
-        })?.get(0) {
+            InternalError(e.to_string())
+        })?.first() {
         None => {
-            Err(MerchantError("merchant does not exist".to_string()))
+            Err(MerchantNotFound)
         }
         Some(row) => {
             Ok(Merchant {
@@ -0,0 +1,92 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use hyper::Uri;
+use hyper::client::HttpConnector;
+use hyper::client::connect::dns::Name;
+use hyper::service::Service;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+// requests to urls merchants configure, like webhook endpoints and authorization endpoints. they have to be https
+// on a public address, allow_local lets http and local addresses through for development
+pub type Client = hyper::Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+pub fn check_url(url: &str, allow_local: bool) -> Result<(), String> {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) if uri.scheme_str() == Some("https") || (allow_local && uri.scheme_str() == Some("http")) => { uri }
+        _ => { return Err("url must be an absolute https url".to_string()); }
+    };
+    let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+    let local = match host.parse::<IpAddr>() {
+        Ok(ip) => { !is_public(ip) }
+        Err(_) => { host.is_empty() || host == "localhost" || host.ends_with(".localhost") || !host.contains('.') }
+    };
+    if local && !allow_local {
+        return Err("url must point to a public host".to_string());
+    }
+    Ok(())
+}
+
+pub fn client(allow_local: bool) -> Client {
+    let mut http = HttpConnector::new_with_resolver(PublicResolver { allow_local });
+    http.enforce_http(false);
+    let https = HttpsConnectorBuilder::new().with_webpki_roots();
+    let https = if allow_local { https.https_or_http() } else { https.https_only() };
+    hyper::Client::builder().build(https.enable_http1().wrap_connector(http))
+}
+
+// urls are checked when they are saved, but a public name can still resolve to a local address later on
+#[derive(Clone)]
+pub struct PublicResolver {
+    allow_local: bool,
+}
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_local = self.allow_local;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|addr| allow_local || is_public(addr.ip())).collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                          format!("{} doesn't resolve to a public address", name)));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => { is_public_v4(ip) }
+        IpAddr::V6(ip) => {
+            match ip.to_ipv4_mapped() {
+                Some(ip) => { is_public_v4(ip) }
+                None => { is_public_v6(ip) }
+            }
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+        || ip.is_documentation() || ip.is_multicast() || a == 0 || a >= 240
+        || (a == 100 && (64..128).contains(&b)) || (a == 192 && b == 0 && c == 0) || (a == 198 && (b == 18 || b == 19)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80 || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
@@ -0,0 +1,74 @@
+use chrono::prelude::*;
+use rand::Rng;
+
+const MASK_CHAR: char = '*';
+const CVV_LENGTH: usize = 3;
+const MIN_PAN_LENGTH: usize = 12;
+const MAX_PAN_LENGTH: usize = 19;
+
+pub struct CardNumber {
+    pub pan: String,
+    pub cvv: String,
+    pub exp_month: i32,
+    pub exp_year: i32,
+}
+
+impl CardNumber {
+    pub fn generate(bin_from: i64, bin_to: i64, pan_length: usize, expiry_months: i32) -> Result<CardNumber, String> {
+        if bin_from <= 0 || bin_from > bin_to {
+            return Err(format!("bin range {}..{} is empty", bin_from, bin_to));
+        }
+        // every pan of the program has the same length and no leading zeros
+        if bin_from.to_string().len() != bin_to.to_string().len() {
+            return Err(format!("bin range {}..{} mixes bins with different numbers of digits", bin_from, bin_to));
+        }
+        if !(MIN_PAN_LENGTH..=MAX_PAN_LENGTH).contains(&pan_length) {
+            return Err(format!("pan length {} is not between {} and {}", pan_length, MIN_PAN_LENGTH, MAX_PAN_LENGTH));
+        }
+        // the bin and the check digit have to fit in the pan
+        if pan_length <= bin_to.to_string().len() {
+            return Err(format!("pan length {} doesn't fit bin {}", pan_length, bin_to));
+        }
+        let mut rng = rand::thread_rng();
+        let mut pan = rng.gen_range(bin_from..=bin_to).to_string();
+        while pan.len() < pan_length - 1 {
+            pan.push(char::from(b'0' + rng.gen_range(0..10)));
+        }
+        pan.push(luhn_check_digit(&pan));
+
+        let cvv = (0..CVV_LENGTH).map(|_| char::from(b'0' + rng.gen_range(0..10))).collect();
+
+        let now = Utc::now();
+        let months = now.year() * 12 + now.month0() as i32 + expiry_months;
+        Ok(CardNumber {
+            pan,
+            cvv,
+            exp_month: months % 12 + 1,
+            exp_year: months / 12,
+        })
+    }
+
+    pub fn masked(&self) -> String {
+        mask(&self.pan)
+    }
+}
+
+// first 6 and last 4 digits stay readable
+pub fn mask(pan: &str) -> String {
+    pan.chars().enumerate().map(|(i, c)| {
+        if i < 6 || i >= pan.len().saturating_sub(4) { c } else { MASK_CHAR }
+    }).collect()
+}
+
+fn luhn_check_digit(payload: &str) -> char {
+    let sum: u32 = payload.chars().rev().enumerate().map(|(i, c)| {
+        let digit = c.to_digit(10).unwrap_or(0);
+        if i % 2 == 0 {
+            let doubled = digit * 2;
+            if doubled > 9 { doubled - 9 } else { doubled }
+        } else {
+            digit
+        }
+    }).sum();
+    char::from(b'0' + ((10 - sum % 10) % 10) as u8)
+}
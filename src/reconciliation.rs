@@ -0,0 +1,80 @@
+use std::env;
+use std::time::Duration;
+use tokio_postgres::GenericClient;
+use crate::db::{DBPool, get_db_conn};
+use crate::error::Errors;
+use crate::error::Errors::InternalError;
+use crate::transaction::{ItemType, TransactionStatus};
+
+const RECONCILIATION_INTERVAL_VAR: &str = "RECONCILIATION_INTERVAL";
+const DEFAULT_RECONCILIATION_INTERVAL: u64 = 60 * 60;
+
+pub struct Drift {
+    pub account_id: i32,
+    pub balance: i64,
+    pub computed: i64,
+    pub pending_debits: i64,
+    pub computed_debits: i64,
+    pub pending_credits: i64,
+    pub computed_credits: i64,
+}
+
+pub async fn run(pool: DBPool) {
+    let period = env::var(RECONCILIATION_INTERVAL_VAR).ok().and_then(|val| val.parse().ok())
+        .unwrap_or(DEFAULT_RECONCILIATION_INTERVAL);
+    let mut interval = tokio::time::interval(Duration::from_secs(period));
+    loop {
+        interval.tick().await;
+        let conn = get_db_conn(&pool).await;
+        match reconcile(&*conn).await {
+            Ok(drifts) => {
+                for drift in drifts.iter() {
+                    warn!("account: {} balance drift: stored {}/{}/{}, computed from items {}/{}/{} \
+                     (posted/pending debits/pending credits)", drift.account_id,
+                          drift.balance, drift.pending_debits, drift.pending_credits,
+                          drift.computed, drift.computed_debits, drift.computed_credits);
+                }
+                info!("reconciliation finished, {} accounts with drift", drifts.len());
+            }
+            Err(e) => { error!("reconciliation failed: {:?}", e) }
+        }
+    }
+}
+
+pub async fn reconcile<C: GenericClient>(conn: &C) -> Result<Vec<Drift>, Errors> {
+    Ok(conn.query("select * from (\
+     select a.id, coalesce(b.balance, 0) as balance, \
+      coalesce(b.pending_debits, 0) as pending_debits, coalesce(b.pending_credits, 0) as pending_credits, \
+      coalesce((select sum(case when i.dest_acc_id = a.id then i.amount else -i.amount end) \
+       from transaction_item i join transaction t on t.id = i.trans_id \
+       where (i.src_acc_id = a.id or i.dest_acc_id = a.id) and t.status = any($1)), 0) as computed, \
+      coalesce((select sum(i.amount) from transaction_item i join transaction t on t.id = i.trans_id \
+       where i.src_acc_id = a.id and t.status = any($2)), 0) \
+      - coalesce((select sum(i.amount) from transaction_item i join transaction t on t.id = i.trans_id \
+       join transaction p on p.id = t.parent_id \
+       where i.src_acc_id = a.id and i.type = $3 and p.status = any($2)), 0) \
+      as computed_debits, \
+      coalesce((select sum(i.amount) from transaction_item i join transaction t on t.id = i.trans_id \
+       where i.dest_acc_id = a.id and t.status = any($2)), 0) \
+      - coalesce((select sum(i.amount) from transaction_item i join transaction t on t.id = i.trans_id \
+       join transaction p on p.id = t.parent_id \
+       where i.dest_acc_id = a.id and i.type = $3 and p.status = any($2)), 0) \
+      as computed_credits \
+     from account a left join account_balance b on b.acc_id = a.id) balances \
+     where balance <> computed or pending_debits <> computed_debits or pending_credits <> computed_credits \
+     order by id", &[&TransactionStatus::posted_db_vals(), &TransactionStatus::open_db_vals(),
+        &ItemType::Principal.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.iter().map(|row| {
+        Drift {
+            account_id: row.get("id"),
+            balance: row.get("balance"),
+            computed: row.get("computed"),
+            pending_debits: row.get("pending_debits"),
+            computed_debits: row.get("computed_debits"),
+            pending_credits: row.get("pending_credits"),
+            computed_credits: row.get("computed_credits"),
+        }
+    }).collect())
+}
@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use tokio_postgres::{GenericClient, Row};
+use crate::error::Errors;
+use crate::error::Errors::{CardRestricted, InternalError, InvalidMerchantData};
+use crate::transaction::MerchantData;
+
+// mcc entries are single codes like "5541" or inclusive ranges like "3000-3299", an empty allow list allows everything
+#[derive(Serialize, Deserialize)]
+pub struct Restrictions {
+    #[serde(rename = "allowedMccs", default)]
+    pub allowed_mccs: Vec<String>,
+    #[serde(rename = "blockedMccs", default)]
+    pub blocked_mccs: Vec<String>,
+    #[serde(rename = "allowedCountries", default)]
+    pub allowed_countries: Vec<String>,
+    #[serde(rename = "blockedCountries", default)]
+    pub blocked_countries: Vec<String>,
+}
+
+impl Restrictions {
+    fn from_row(row: &Row) -> Restrictions {
+        Restrictions {
+            allowed_mccs: row.get("allowed_mccs"),
+            blocked_mccs: row.get("blocked_mccs"),
+            allowed_countries: row.get("allowed_countries"),
+            blocked_countries: row.get("blocked_countries"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Errors> {
+        for entry in self.allowed_mccs.iter().chain(self.blocked_mccs.iter()) {
+            mcc_range(entry).ok_or_else(|| {
+                InvalidMerchantData(format!("{} is not an mcc or mcc range", entry))
+            })?;
+        }
+        for country in self.allowed_countries.iter().chain(self.blocked_countries.iter()) {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err(InvalidMerchantData(format!("{} is not an ISO 3166 alpha-2 code", country)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mcc_range(entry: &str) -> Option<(u16, u16)> {
+    let parse = |mcc: &str| {
+        if mcc.len() == 4 { mcc.parse::<u16>().ok() } else { None }
+    };
+    match entry.split_once('-') {
+        None => { parse(entry).map(|mcc| (mcc, mcc)) }
+        Some((from, to)) => {
+            match (parse(from), parse(to)) {
+                (Some(from), Some(to)) if from <= to => { Some((from, to)) }
+                _ => { None }
+            }
+        }
+    }
+}
+
+fn mcc_matches(entries: &[String], mcc: &str) -> bool {
+    let mcc: u16 = match mcc.parse() {
+        Ok(mcc) => { mcc }
+        Err(_) => { return false; }
+    };
+    entries.iter().filter_map(|entry| mcc_range(entry)).any(|(from, to)| from <= mcc && mcc <= to)
+}
+
+pub async fn save<C: GenericClient>(conn: &C, card_id: i32, restrictions: &Restrictions) -> Result<(), Errors> {
+    restrictions.validate()?;
+    conn.execute("insert into card_restriction (card_id, allowed_mccs, blocked_mccs, allowed_countries, \
+     blocked_countries, updated) values ($1, $2, $3, $4, $5, now()) \
+     on conflict (card_id) do update set allowed_mccs = $2, blocked_mccs = $3, allowed_countries = $4, \
+     blocked_countries = $5, updated = now()",
+                 &[&card_id, &restrictions.allowed_mccs, &restrictions.blocked_mccs,
+                     &restrictions.allowed_countries, &restrictions.blocked_countries]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    info!("restrictions of card: {} were updated", card_id);
+    Ok(())
+}
+
+pub async fn check<C: GenericClient>(conn: &C, card_id: i32, merchant: &MerchantData) -> Result<(), Errors> {
+    let restrictions = match conn.query("select * from card_restriction where card_id = $1", &[&card_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Ok(()); }
+        Some(row) => { Restrictions::from_row(row) }
+    };
+
+    if mcc_matches(&restrictions.blocked_mccs, &merchant.mcc)
+        || (!restrictions.allowed_mccs.is_empty() && !mcc_matches(&restrictions.allowed_mccs, &merchant.mcc)) {
+        return Err(CardRestricted(format!("card can't be used at merchants with mcc {}", merchant.mcc)));
+    }
+    if restrictions.blocked_countries.contains(&merchant.country)
+        || (!restrictions.allowed_countries.is_empty() && !restrictions.allowed_countries.contains(&merchant.country)) {
+        return Err(CardRestricted(format!("card can't be used at merchants in {}", merchant.country)));
+    }
+    Ok(())
+}
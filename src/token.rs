@@ -1,16 +1,32 @@
 use crate::db::{DBPool, get_db_conn, DBConn};
-use warp::reply::{Json, json};
+use warp::reply::Response;
 use crate::merchant::get_merchant_by_id;
 use hmac::{Hmac, NewMac};
 use sha2::{Sha256, Digest};
-use std::collections::BTreeMap;
-use jwt::{SignWithKey, VerifyWithKey};
+use jwt::{SignWithKey, VerifyWithKey, RegisteredClaims};
 use serde::{Serialize, Deserialize};
-use crate::{ErrorResponse, Errors};
-use crate::Errors::MerchantError;
+use chrono::prelude::*;
+use chrono::Duration;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use crate::error::{Errors, reply};
+use crate::error::Errors::{InternalError, InvalidCredentials, MerchantNotFound, Unauthorized};
+use std::env;
 
 const SECRET: &[u8; 44] = b"UCnmDHn9QS+GqLR5Gkyfw00fykPgW8R9b9uALi4xHEA=";
 
+const ACCESS_TOKEN_LIFETIME_VAR: &str = "ACCESS_TOKEN_LIFETIME";
+const REFRESH_TOKEN_LIFETIME_VAR: &str = "REFRESH_TOKEN_LIFETIME";
+const DEFAULT_ACCESS_TOKEN_LIFETIME: i64 = 15 * 60;
+const DEFAULT_REFRESH_TOKEN_LIFETIME: i64 = 30 * 24 * 60 * 60;
+const TOKEN_ID_LENGTH: usize = 32;
+const REFRESH_TOKEN_LENGTH: usize = 64;
+
+/// The merchant a request was authenticated as.
+pub struct AuthMerchant {
+    pub id: i32,
+}
+
 #[derive(Deserialize)]
 pub struct TokenRequest {
     #[serde(rename = "merchantId")]
@@ -18,49 +34,154 @@ pub struct TokenRequest {
     pub secret: String,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+}
+
 #[derive(Serialize)]
 pub struct TokenResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    pub revoked: bool,
 }
 
-pub async fn create_token_handler(db_pool: DBPool, req: TokenRequest) -> Result<Json, warp::Rejection> {
+pub async fn create_token_handler(db_pool: DBPool, req: TokenRequest) -> Result<Response, warp::Rejection> {
     info!("Auth method was called");
     let conn = get_db_conn(&db_pool).await;
-    match create_token(&conn, req.merchant_id, &req.secret).await {
-        Ok(token) => {
-            Ok(json(&TokenResponse {
-                token
-            }))
-        }
-        Err(MerchantError(message)) => { Ok(json(&ErrorResponse { error: message })) }
-        _ => { Ok(json(&ErrorResponse { error: "General error".to_string() })) }
-    }
+    reply(create_token(&conn, req.merchant_id, &req.secret).await)
+}
+
+pub async fn refresh_token_handler(db_pool: DBPool, req: RefreshRequest) -> Result<Response, warp::Rejection> {
+    let conn = get_db_conn(&db_pool).await;
+    reply(refresh_token(&conn, &req.refresh_token).await)
 }
 
-async fn create_token(conn: &DBConn, merchant_id: i32, secret: &String) -> Result<String, Errors> {
-    let merchant = get_merchant_by_id(conn, merchant_id).await?;
+pub async fn revoke_token_handler(db_pool: DBPool, req: RefreshRequest) -> Result<Response, warp::Rejection> {
+    let conn = get_db_conn(&db_pool).await;
+    reply(revoke_token(&conn, &req.refresh_token).await.map(|revoked| RevokeResponse { revoked }))
+}
+
+async fn create_token(conn: &DBConn, merchant_id: i32, secret: &str) -> Result<TokenResponse, Errors> {
+    // an unknown merchant fails like a wrong secret, so merchant ids can't be probed
+    let merchant = match get_merchant_by_id(conn, merchant_id).await {
+        Err(MerchantNotFound) => { return Err(InvalidCredentials); }
+        result => { result? }
+    };
     if merchant.secret != sha256_hash(secret) {
-        return Err(Errors::MerchantError("Secret is not valid".to_string()));
+        return Err(InvalidCredentials);
+    }
+    issue_tokens(conn, merchant.id).await
+}
+
+async fn refresh_token(conn: &DBConn, refresh_token: &str) -> Result<TokenResponse, Errors> {
+    // the refresh token is rotated: the presented one is revoked in the same statement that looks it up,
+    // so a leaked token can be used at most once
+    let merchant_id: i32 = match conn.query(
+        "update refresh_token set revoked = true \
+         where token_hash = $1 and revoked = false and expires > now() returning merch_id",
+        &[&sha256_hash(refresh_token)]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Err(Unauthorized("refresh token is not valid".to_string())); }
+        Some(row) => { row.get("merch_id") }
+    };
+    issue_tokens(conn, merchant_id).await
+}
+
+async fn revoke_token(conn: &DBConn, refresh_token: &str) -> Result<bool, Errors> {
+    let updated = conn.execute("update refresh_token set revoked = true where token_hash = $1 and revoked = false",
+                               &[&sha256_hash(refresh_token)]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    if updated == 0 {
+        return Err(Unauthorized("refresh token is not valid".to_string()));
     }
+    info!("refresh token was revoked");
+    Ok(true)
+}
+
+async fn issue_tokens(conn: &DBConn, merchant_id: i32) -> Result<TokenResponse, Errors> {
+    let now = Utc::now();
+    let access_lifetime = lifetime_from_env(ACCESS_TOKEN_LIFETIME_VAR, DEFAULT_ACCESS_TOKEN_LIFETIME);
+    let refresh_lifetime = lifetime_from_env(REFRESH_TOKEN_LIFETIME_VAR, DEFAULT_REFRESH_TOKEN_LIFETIME);
+
     let key: Hmac<Sha256> = Hmac::new_from_slice(SECRET).unwrap();
-    let mut claims = BTreeMap::new();
-    claims.insert("sub", merchant_id.to_string());
+    let claims = RegisteredClaims {
+        subject: Some(merchant_id.to_string()),
+        issued_at: Some(now.timestamp() as u64),
+        not_before: Some(now.timestamp() as u64),
+        expiration: Some((now + Duration::seconds(access_lifetime)).timestamp() as u64),
+        json_web_token_id: Some(random_string(TOKEN_ID_LENGTH)),
+        ..Default::default()
+    };
+    let token = claims.sign_with_key(&key).map_err(|e| {
+        InternalError(e.to_string())
+    })?;
 
-    Ok(claims.sign_with_key(&key).unwrap())
+    let refresh_token = random_string(REFRESH_TOKEN_LENGTH);
+    let expires = now + Duration::seconds(refresh_lifetime);
+    conn.execute("insert into refresh_token (id, token_hash, merch_id, created, expires, revoked) \
+     values (default, $1, $2, now(), $3, false)", &[&sha256_hash(&refresh_token), &merchant_id, &expires]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    Ok(TokenResponse {
+        token,
+        refresh_token,
+        expires_in: access_lifetime,
+    })
 }
 
-pub fn validate_auth_header(auth: String) -> i32 {
-    validate_token(auth.replace("Bearer", "").trim())
+pub async fn validate_auth_header(auth: Option<String>) -> Result<AuthMerchant, warp::Rejection> {
+    let header = auth.ok_or_else(|| {
+        warp::reject::custom(Unauthorized("authorization header is missing".to_string()))
+    })?;
+    let token = header.trim().strip_prefix("Bearer").map(str::trim).ok_or_else(|| {
+        warp::reject::custom(Unauthorized("authorization header is not a bearer token".to_string()))
+    })?;
+    validate_token(token).map(|id| AuthMerchant { id }).map_err(warp::reject::custom)
 }
 
-fn validate_token(token: &str) -> i32 {
+fn validate_token(token: &str) -> Result<i32, Errors> {
     let key: Hmac<Sha256> = Hmac::new_from_slice(SECRET).unwrap();
-    let claims: BTreeMap<String, String> = token.verify_with_key(&key).unwrap();
-    claims["sub"].parse().unwrap()
+    let claims: RegisteredClaims = token.verify_with_key(&key).map_err(|_| {
+        Unauthorized("token is not valid".to_string())
+    })?;
+
+    let now = Utc::now().timestamp() as u64;
+    match claims.expiration {
+        Some(exp) if exp > now => {}
+        _ => { return Err(Unauthorized("token is expired".to_string())); }
+    }
+    if claims.not_before.is_some_and(|nbf| nbf > now) {
+        return Err(Unauthorized("token is not yet valid".to_string()));
+    }
+
+    claims.subject.and_then(|sub| sub.parse().ok()).ok_or_else(|| {
+        Unauthorized("token subject is not valid".to_string())
+    })
+}
+
+fn lifetime_from_env(var: &str, default: i64) -> i64 {
+    env::var(var).ok().and_then(|val| val.parse().ok()).unwrap_or(default)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
 }
 
-fn sha256_hash(text: &String) -> String {
+pub fn sha256_hash(text: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(text);
     base64::encode(hasher.finalize())
-}
\ No newline at end of file
+}
@@ -1,15 +1,39 @@
-use warp::reply::{Json, json};
+use warp::reply::Response;
 use serde::{Serialize, Deserialize};
-use crate::token::validate_auth_header;
+use crate::token::AuthMerchant;
 use crate::db::{DBPool, get_db_conn, DBConn};
-use crate::{account, ErrorResponse, Errors};
-use crate::Errors::{TransactionError, AccountError};
-use tokio_postgres::Row;
+use crate::{account, idempotency, webhook};
+use crate::error::{Errors, reply};
+use crate::error::Errors::{CaptureExceedsAuthorization, CurrencyMismatch, IdempotencyConflict, InsufficientFunds,
+                           InternalError, InvalidAmount, InvalidFilter, InvalidMerchantData, InvalidStatusTransition,
+                           NotReversible, RefundExceedsOriginal, TransactionNotFound};
+use tokio_postgres::{GenericClient, Row, Transaction};
+use tokio_postgres::error::SqlState;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::env;
+use rand::Rng;
 
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_DESCRIPTOR_LENGTH: usize = 40;
+const HOLD_LIFETIME_VAR: &str = "HOLD_LIFETIME";
+const DEFAULT_HOLD_LIFETIME: i64 = 7 * 24 * 60 * 60;
+// a spend is only pending while it's checked and the merchant is asked, one left behind by a crash is expired after this
+const PENDING_LIFETIME: i64 = 5 * 60;
+
+#[derive(PartialEq)]
 pub enum TransactionType {
     Fund,
     VirtualCardDeposit,
     VirtualCardWithdraw,
+    Reversal,
+    Refund,
+    CardAuthorization,
+    CardCapture,
+    CardSweep,
+    ForcePost,
+    JitFunding,
 }
 
 impl TransactionType {
@@ -18,23 +42,259 @@ impl TransactionType {
             TransactionType::Fund => { "fund" }
             TransactionType::VirtualCardDeposit => { "virtual_card_deposit" }
             TransactionType::VirtualCardWithdraw => { "virtual_card_withdraw" }
+            TransactionType::Reversal => { "reversal" }
+            TransactionType::Refund => { "refund" }
+            TransactionType::CardAuthorization => { "card_authorization" }
+            TransactionType::CardCapture => { "card_capture" }
+            TransactionType::CardSweep => { "card_sweep" }
+            TransactionType::ForcePost => { "force_post" }
+            TransactionType::JitFunding => { "jit_funding" }
+        }
+    }
+
+    fn from_db_val(val: &str) -> Option<TransactionType> {
+        match val {
+            "fund" => { Some(TransactionType::Fund) }
+            "virtual_card_deposit" => { Some(TransactionType::VirtualCardDeposit) }
+            "virtual_card_withdraw" => { Some(TransactionType::VirtualCardWithdraw) }
+            "reversal" => { Some(TransactionType::Reversal) }
+            "refund" => { Some(TransactionType::Refund) }
+            "card_authorization" => { Some(TransactionType::CardAuthorization) }
+            "card_capture" => { Some(TransactionType::CardCapture) }
+            "card_sweep" => { Some(TransactionType::CardSweep) }
+            "force_post" => { Some(TransactionType::ForcePost) }
+            "jit_funding" => { Some(TransactionType::JitFunding) }
+            _ => { None }
         }
     }
 }
 
+const STATUSES: [TransactionStatus; 8] = [TransactionStatus::Pending, TransactionStatus::Authorized,
+    TransactionStatus::Completed, TransactionStatus::Failed, TransactionStatus::Reversed, TransactionStatus::Expired,
+    TransactionStatus::Captured, TransactionStatus::Voided];
+
+#[derive(PartialEq, Clone)]
 pub enum TransactionStatus {
-    Completed
+    Pending,
+    Authorized,
+    Completed,
+    Failed,
+    Reversed,
+    Expired,
+    Captured,
+    Voided,
 }
 
 impl TransactionStatus {
     fn to_db_val(&self) -> &'static str {
         match self {
+            TransactionStatus::Pending => { "pending" }
+            TransactionStatus::Authorized => { "authorized" }
             TransactionStatus::Completed => { "completed" }
+            TransactionStatus::Failed => { "failed" }
+            TransactionStatus::Reversed => { "reversed" }
+            TransactionStatus::Expired => { "expired" }
+            TransactionStatus::Captured => { "captured" }
+            TransactionStatus::Voided => { "voided" }
+        }
+    }
+
+    fn from_db_val(val: &str) -> Option<TransactionStatus> {
+        match val {
+            "pending" => { Some(TransactionStatus::Pending) }
+            "authorized" => { Some(TransactionStatus::Authorized) }
+            "completed" => { Some(TransactionStatus::Completed) }
+            "failed" => { Some(TransactionStatus::Failed) }
+            "reversed" => { Some(TransactionStatus::Reversed) }
+            "expired" => { Some(TransactionStatus::Expired) }
+            "captured" => { Some(TransactionStatus::Captured) }
+            "voided" => { Some(TransactionStatus::Voided) }
+            _ => { None }
+        }
+    }
+
+    // items of posted transactions count towards the ledger balance, items of open ones are only held as pending
+    fn is_posted(&self) -> bool {
+        matches!(self, TransactionStatus::Completed | TransactionStatus::Reversed)
+    }
+
+    fn is_open(&self) -> bool {
+        matches!(self, TransactionStatus::Pending | TransactionStatus::Authorized)
+    }
+
+    pub fn posted_db_vals() -> Vec<&'static str> {
+        STATUSES.iter().filter(|status| status.is_posted()).map(TransactionStatus::to_db_val).collect()
+    }
+
+    pub fn open_db_vals() -> Vec<&'static str> {
+        STATUSES.iter().filter(|status| status.is_open()).map(TransactionStatus::to_db_val).collect()
+    }
+
+    fn can_transition_to(&self, next: &TransactionStatus) -> bool {
+        match self {
+            TransactionStatus::Pending => {
+                matches!(next, TransactionStatus::Authorized | TransactionStatus::Completed
+                    | TransactionStatus::Failed | TransactionStatus::Expired)
+            }
+            TransactionStatus::Authorized => {
+                matches!(next, TransactionStatus::Completed | TransactionStatus::Failed
+                    | TransactionStatus::Reversed | TransactionStatus::Expired
+                    | TransactionStatus::Captured | TransactionStatus::Voided)
+            }
+            TransactionStatus::Completed => { *next == TransactionStatus::Reversed }
+            TransactionStatus::Failed | TransactionStatus::Reversed | TransactionStatus::Expired
+            | TransactionStatus::Captured | TransactionStatus::Voided => { false }
         }
     }
 }
 
-#[derive(Deserialize)]
+pub enum ItemType {
+    Principal,
+    Fee,
+}
+
+impl ItemType {
+    pub fn to_db_val(&self) -> &'static str {
+        match self {
+            ItemType::Principal => { "principal" }
+            ItemType::Fee => { "fee" }
+        }
+    }
+}
+
+pub struct Balance {
+    pub posted: i64,
+    pub pending_debits: i64,
+    pub pending_credits: i64,
+}
+
+impl Balance {
+    pub fn available(&self) -> i64 {
+        self.posted - self.pending_debits
+    }
+}
+
+pub struct Leg {
+    pub src_acc_id: i32,
+    pub dest_acc_id: i32,
+    pub amount: i32,
+}
+
+// a completed transaction together with what was already refunded from it
+pub struct Refundable {
+    pub id: i32,
+    pub principal: Leg,
+    pub fee: Option<Leg>,
+    pub refunded: i32,
+    pub refunded_fee: i32,
+}
+
+impl Refundable {
+    fn remaining(&self) -> i32 {
+        self.principal.amount - self.refunded
+    }
+
+    // fees are returned in proportion to the refunded amount, the last refund returns whatever fee is left
+    fn fee_for(&self, amount: i32) -> i32 {
+        let fee = self.fee.as_ref().map(|leg| leg.amount).unwrap_or(0);
+        if amount == self.remaining() {
+            fee - self.refunded_fee
+        } else {
+            ((fee as i64) * (amount as i64) / (self.principal.amount as i64)) as i32
+        }
+    }
+}
+
+// an authorization that still holds funds, the captures posted against it are its children
+pub struct Hold {
+    pub id: i32,
+    pub principal: Leg,
+    pub captured: i32,
+}
+
+impl Hold {
+    pub fn remaining(&self) -> i32 {
+        self.principal.amount - self.captured
+    }
+}
+
+// where a card was spent, as reported by the acquirer
+#[derive(Serialize, Deserialize)]
+pub struct MerchantData {
+    #[serde(rename = "merchantDescriptor")]
+    pub descriptor: String,
+    pub mcc: String,
+    pub country: String,
+}
+
+impl MerchantData {
+    pub fn validate(&self) -> Result<(), Errors> {
+        if self.descriptor.trim().is_empty() || self.descriptor.chars().count() > MAX_DESCRIPTOR_LENGTH {
+            return Err(InvalidMerchantData(format!("merchantDescriptor must be 1 to {} characters long",
+                                                   MAX_DESCRIPTOR_LENGTH)));
+        }
+        if self.mcc.len() != 4 || !self.mcc.chars().all(|c| c.is_ascii_digit()) {
+            return Err(InvalidMerchantData("mcc must be 4 digits".to_string()));
+        }
+        if self.country.len() != 2 || !self.country.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(InvalidMerchantData("country must be an ISO 3166 alpha-2 code".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub struct CardSpend {
+    pub daily: i64,
+    pub weekly: i64,
+    pub monthly: i64,
+    pub lifetime: i64,
+    pub daily_count: i64,
+    pub weekly_count: i64,
+    pub monthly_count: i64,
+}
+
+pub struct Order {
+    pub merch_id: i32,
+    pub order_id: String,
+    // postings the API makes on its own are keyed by system_ref instead of order_id, so no orderId a merchant picks
+    // can collide with them
+    pub system: bool,
+    pub request_hash: String,
+    // the pending transaction that claimed the order, postings made with the order complete it
+    pub pending_id: Option<i32>,
+}
+
+impl Order {
+    pub fn new<T: Serialize>(merch_id: i32, order_id: String, req: &T) -> Order {
+        Order {
+            merch_id,
+            order_id,
+            system: false,
+            request_hash: idempotency::request_hash(req),
+            pending_id: None,
+        }
+    }
+
+    pub fn pending(self, trans_id: i32) -> Order {
+        Order {
+            pending_id: Some(trans_id),
+            ..self
+        }
+    }
+
+    pub fn system<T: Serialize>(merch_id: i32, reference: String, req: &T) -> Order {
+        Order {
+            system: true,
+            ..Order::new(merch_id, reference, req)
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        if self.system { "system_ref" } else { "order_id" }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FundRequest {
     #[serde(rename = "accountId")]
     pub account_id: i32,
@@ -48,38 +308,205 @@ pub struct FundResponse {
     pub transaction_id: i32,
 }
 
-pub async fn fund_account_handler(pool: DBPool, auth: String, req: FundRequest) -> Result<Json, warp::Rejection> {
-    validate_auth_header(auth);
+#[derive(Serialize, Deserialize)]
+pub struct ReverseRequest {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefundRequest {
+    pub amount: i32,
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+}
+
+#[derive(Serialize)]
+pub struct ReversalResponse {
+    pub transaction_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(rename = "type")]
+    pub trans_type: Option<String>,
+    pub status: Option<String>,
+    #[serde(rename = "orderId")]
+    pub order_id: Option<String>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ItemView {
+    pub id: i32,
+    #[serde(rename = "type")]
+    pub item_type: String,
+    pub amount: i32,
+    pub src_acc_id: i32,
+    pub dest_acc_id: i32,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionView {
+    pub id: i32,
+    #[serde(rename = "type")]
+    pub trans_type: String,
+    pub status: String,
+    pub order_id: Option<String>,
+    pub system_ref: Option<String>,
+    pub parent_id: Option<i32>,
+    pub card_id: Option<i32>,
+    pub merchant_descriptor: Option<String>,
+    pub mcc: Option<String>,
+    pub merchant_country: Option<String>,
+    pub auth_code: Option<String>,
+    pub amount: i64,
+    pub fee: i64,
+    pub created: DateTime<Utc>,
+    pub accounts: Vec<i32>,
+    pub items: Vec<ItemView>,
+    pub status_history: Vec<StatusChange>,
+}
+
+#[derive(Serialize)]
+pub struct StatusChange {
+    pub status: String,
+    pub created: DateTime<Utc>,
+}
+
+// data of the transaction.created and transaction.status_changed webhook events
+#[derive(Serialize)]
+pub struct TransactionEvent<'a> {
+    pub transaction_id: i32,
+    #[serde(rename = "type")]
+    pub trans_type: &'a str,
+    pub status: &'a str,
+    pub order_id: Option<&'a str>,
+    pub system_ref: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+pub struct OrderQuery {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    pub transactions: Vec<TransactionView>,
+    pub next_cursor: Option<String>,
+}
+
+pub async fn fund_account_handler(pool: DBPool, merchant: AuthMerchant, req: FundRequest) -> Result<Response, warp::Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(fund(&mut conn, req, merchant.id).await.map(|id| FundResponse {
+        transaction_id: id
+    }))
+}
+
+pub async fn reverse_handler(id: i32, pool: DBPool, merchant: AuthMerchant, req: ReverseRequest) -> Result<Response, warp::Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(reverse(&mut conn, id, req, merchant.id).await.map(|id| ReversalResponse {
+        transaction_id: id
+    }))
+}
+
+pub async fn refund_handler(id: i32, pool: DBPool, merchant: AuthMerchant, req: RefundRequest) -> Result<Response, warp::Rejection> {
+    let mut conn = get_db_conn(&pool).await;
+    reply(refund(&mut conn, id, req, merchant.id).await.map(|id| ReversalResponse {
+        transaction_id: id
+    }))
+}
+
+pub async fn history_handler(id: i32, query: HistoryQuery, pool: DBPool, merchant: AuthMerchant) -> Result<Response, warp::Rejection> {
     let conn = get_db_conn(&pool).await;
-    match fund(&conn, req).await {
-        Ok(id) => {
-            Ok(json(&FundResponse {
-                transaction_id: id
-            }))
-        }
-        Err(AccountError(message)) => {
-            Ok(json(&ErrorResponse { error: message }))
-        }
-        Err(TransactionError(message)) => {
-            Ok(json(&ErrorResponse { error: message }))
-        }
-        _ => {
-            Ok(json(&ErrorResponse { error: "general error".to_string() }))
-        }
-    }
+    reply(get_account_history(&*conn, id, merchant.id, query).await)
 }
 
-pub async fn deposit(conn: &DBConn, src_account_id: i32, dest_account_id: i32, fee_account_id: i32, amount: i32,
-                     trans_type: TransactionType, order_id: String) -> Result<i32, Errors> {
+pub async fn get_account_history<C: GenericClient>(conn: &C, account_id: i32, merch_id: i32,
+                                                   query: HistoryQuery) -> Result<HistoryResponse, Errors> {
+    account::get_active_by_id_and_merchant(conn, account_id, merch_id).await?;
+    get_history(conn, account_id, query).await
+}
+
+pub async fn get_handler(id: i32, pool: DBPool, merchant: AuthMerchant) -> Result<Response, warp::Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_by_id(&*conn, id, merchant.id).await)
+}
+
+pub async fn get_by_order_handler(query: OrderQuery, pool: DBPool, merchant: AuthMerchant) -> Result<Response, warp::Rejection> {
+    let conn = get_db_conn(&pool).await;
+    reply(get_by_order_id(&*conn, &query.order_id, merchant.id).await)
+}
+
+pub async fn get_by_id<C: GenericClient>(conn: &C, id: i32, merch_id: i32) -> Result<TransactionView, Errors> {
+    let rows = conn.query("select id, type, status, order_id, system_ref, parent_id, card_id, merchant_descriptor, mcc, \
+     merchant_country, auth_code, created from transaction where id=$1 and merch_id=$2",
+                          &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    to_views(conn, &rows).await?.pop().ok_or(TransactionNotFound)
+}
+
+pub async fn get_by_order_id<C: GenericClient>(conn: &C, order_id: &str, merch_id: i32) -> Result<TransactionView, Errors> {
+    let rows = conn.query("select id, type, status, order_id, system_ref, parent_id, card_id, merchant_descriptor, mcc, \
+     merchant_country, auth_code, created from transaction where order_id=$1 and merch_id=$2 \
+     order by status = $3, id desc limit 1",
+                          &[&order_id, &merch_id, &TransactionStatus::Failed.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    to_views(conn, &rows).await?.pop().ok_or(TransactionNotFound)
+}
+
+pub async fn get_by_system_ref<C: GenericClient>(conn: &C, system_ref: &str, merch_id: i32) -> Result<TransactionView, Errors> {
+    let rows = conn.query("select id, type, status, order_id, system_ref, parent_id, card_id, merchant_descriptor, mcc, \
+     merchant_country, auth_code, created from transaction where system_ref=$1 and merch_id=$2 \
+     order by status = $3, id desc limit 1",
+                          &[&system_ref, &merch_id, &TransactionStatus::Failed.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    to_views(conn, &rows).await?.pop().ok_or(TransactionNotFound)
+}
+
+pub async fn begin(conn: &mut DBConn) -> Result<Transaction<'_>, Errors> {
+    conn.transaction().await.map_err(|e| {
+        InternalError(e.to_string())
+    })
+}
+
+pub async fn commit(tx: Transaction<'_>) -> Result<(), Errors> {
+    tx.commit().await.map_err(|e| {
+        InternalError(e.to_string())
+    })
+}
+
+pub async fn deposit(conn: &Transaction<'_>, src_account_id: i32, dest_account_id: i32, fee_account_id: i32, amount: i32,
+                     trans_type: TransactionType, order: Order) -> Result<i32, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &trans_type, &order).await? {
+        return Ok(trans_id);
+    }
+    if amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    account::lock_by_id(conn, src_account_id).await?;
     let fee = calculate_fee(conn, amount, &trans_type, dest_account_id).await?;
 
     if get_sum(conn, src_account_id).await? - (amount as i64) < 0 {
-        return Err(TransactionError("source account does not have enough funds".to_string()));
+        return Err(InsufficientFunds);
     }
 
-    let trans_id = create(conn, src_account_id, dest_account_id, amount, &trans_type, order_id).await?;
+    let trans_id = create(conn, src_account_id, dest_account_id, amount, &trans_type, order,
+                          TransactionStatus::Completed).await?;
     if fee > 0 {
-        create_item(conn, fee, trans_id, dest_account_id, fee_account_id).await?;
+        create_item(conn, fee, trans_id, dest_account_id, fee_account_id, ItemType::Fee,
+                    TransactionStatus::Completed).await?;
     }
 
     info!("transaction with type: {} was created",trans_type.to_db_val());
@@ -87,24 +514,412 @@ pub async fn deposit(conn: &DBConn, src_account_id: i32, dest_account_id: i32, f
     Ok(trans_id)
 }
 
-pub async fn fund(conn: &DBConn, req: FundRequest) -> Result<i32, Errors> {
-    let trans_id = create(conn, account::CASH_ACCOUNT_ID, req.account_id, req.amount,
-                          &TransactionType::Fund, req.order_id).await?;
+pub async fn fund(conn: &mut DBConn, req: FundRequest, merch_id: i32) -> Result<i32, Errors> {
+    if req.amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    let tx = begin(conn).await?;
+    let order = Order::new(merch_id, req.order_id.clone(), &req);
+    if let Some(trans_id) = find_by_order(&tx, &TransactionType::Fund, &order).await? {
+        return Ok(trans_id);
+    }
+    account::get_active_by_id_and_merchant(&tx, req.account_id, merch_id).await?;
+    let trans_id = create(&tx, account::CASH_ACCOUNT_ID, req.account_id, req.amount,
+                          &TransactionType::Fund, order, TransactionStatus::Completed).await?;
+    commit(tx).await?;
     info!("transaction with type: {} was created",TransactionType::Fund.to_db_val());
     Ok(trans_id)
 }
 
-pub async fn withdraw(conn: &DBConn, src_account_id: i32, dest_account_id: i32, fee_account_id: i32, amount: i32,
-                      trans_type: TransactionType, order_id: String) -> Result<i32, Errors> {
+pub async fn reverse(conn: &mut DBConn, id: i32, req: ReverseRequest, merch_id: i32) -> Result<i32, Errors> {
+    let order = Order::new(merch_id, req.order_id.clone(), &(id, &req));
+    reverse_order(conn, id, order, merch_id).await
+}
+
+pub async fn reverse_order(conn: &mut DBConn, id: i32, order: Order, merch_id: i32) -> Result<i32, Errors> {
+    let tx = begin(conn).await?;
+    if let Some(trans_id) = find_by_order(&tx, &TransactionType::Reversal, &order).await? {
+        return Ok(trans_id);
+    }
+    let original = get_refundable(&tx, id, merch_id).await?;
+    let amount = original.remaining();
+    let trans_id = compensate(&tx, &original, amount, TransactionType::Reversal, order).await?;
+    set_status(&tx, id, TransactionStatus::Reversed).await?;
+    commit(tx).await?;
+    info!("transaction: {} was reversed by transaction: {}", id, trans_id);
+    Ok(trans_id)
+}
+
+pub async fn refund(conn: &mut DBConn, id: i32, req: RefundRequest, merch_id: i32) -> Result<i32, Errors> {
+    if req.amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    let tx = begin(conn).await?;
+    let order = Order::new(merch_id, req.order_id.clone(), &(id, &req));
+    if let Some(trans_id) = find_by_order(&tx, &TransactionType::Refund, &order).await? {
+        return Ok(trans_id);
+    }
+    let original = get_refundable(&tx, id, merch_id).await?;
+    if req.amount > original.remaining() {
+        return Err(RefundExceedsOriginal);
+    }
+    let trans_id = compensate(&tx, &original, req.amount, TransactionType::Refund, order).await?;
+    if req.amount == original.remaining() {
+        set_status(&tx, id, TransactionStatus::Reversed).await?;
+    }
+    commit(tx).await?;
+    info!("transaction: {} was refunded by transaction: {}", id, trans_id);
+    Ok(trans_id)
+}
+
+async fn get_refundable(conn: &Transaction<'_>, id: i32, merch_id: i32) -> Result<Refundable, Errors> {
+    let (trans_type, status) = match conn.query("select type, status from transaction where id=$1 and merch_id=$2 for update",
+                                                &[&id, &merch_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Err(TransactionNotFound); }
+        Some(row) => {
+            (TransactionType::from_db_val(row.get("type")), TransactionStatus::from_db_val(row.get("status")))
+        }
+    };
+    if matches!(trans_type, Some(TransactionType::Reversal) | Some(TransactionType::Refund)) {
+        return Err(NotReversible("reversals and refunds can't be reversed".to_string()));
+    }
+    if status != Some(TransactionStatus::Completed) {
+        return Err(NotReversible("only completed transactions can be reversed or refunded".to_string()));
+    }
+
+    let items = conn.query("select type, amount, src_acc_id, dest_acc_id from transaction_item where trans_id=$1",
+                           &[&id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let leg = |item_type: ItemType| {
+        items.iter().find(|row| row.get::<_, &str>("type") == item_type.to_db_val()).map(|row| {
+            Leg {
+                src_acc_id: row.get("src_acc_id"),
+                dest_acc_id: row.get("dest_acc_id"),
+                amount: row.get("amount"),
+            }
+        })
+    };
+    let principal = leg(ItemType::Principal).ok_or(TransactionNotFound)?;
+    let fee = leg(ItemType::Fee);
+
+    let refunded_rows = conn.query("select i.type, sum(i.amount) as amount from transaction_item i \
+     join transaction t on t.id = i.trans_id where t.parent_id = $1 group by i.type", &[&id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let refunded = |item_type: ItemType| {
+        refunded_rows.iter().find(|row| row.get::<_, &str>("type") == item_type.to_db_val())
+            .map(|row| row.get::<_, i64>("amount") as i32).unwrap_or(0)
+    };
+
+    Ok(Refundable {
+        id,
+        principal,
+        fee,
+        refunded: refunded(ItemType::Principal),
+        refunded_fee: refunded(ItemType::Fee),
+    })
+}
+
+// posts legs that move the amount and its share of the fee back to where they came from
+async fn compensate(conn: &Transaction<'_>, original: &Refundable, amount: i32, trans_type: TransactionType,
+                    order: Order) -> Result<i32, Errors> {
+    let debited_account_id = original.principal.dest_acc_id;
+    account::lock_by_id(conn, debited_account_id).await?;
+    if debited_account_id != account::CASH_ACCOUNT_ID && get_sum(conn, debited_account_id).await? < amount as i64 {
+        return Err(InsufficientFunds);
+    }
+
+    let fee = original.fee_for(amount);
+    let trans_id = create(conn, debited_account_id, original.principal.src_acc_id, amount, &trans_type, order,
+                          TransactionStatus::Completed).await?;
+    set_parent(conn, trans_id, original.id).await?;
+    if let Some(fee_leg) = &original.fee {
+        if fee > 0 {
+            create_item(conn, fee, trans_id, fee_leg.dest_acc_id, fee_leg.src_acc_id, ItemType::Fee,
+                        TransactionStatus::Completed).await?;
+        }
+    }
+    info!("transaction with type: {} was created", trans_type.to_db_val());
+    Ok(trans_id)
+}
+
+pub async fn authorize(conn: &Transaction<'_>, src_account_id: i32, dest_account_id: i32, amount: i32,
+                       order: Order) -> Result<i32, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &TransactionType::CardAuthorization, &order).await? {
+        return Ok(trans_id);
+    }
+    if amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    account::lock_by_id(conn, src_account_id).await?;
+    if get_sum(conn, src_account_id).await? - (amount as i64) < 0 {
+        return Err(InsufficientFunds);
+    }
+
+    let trans_id = create(conn, src_account_id, dest_account_id, amount, &TransactionType::CardAuthorization, order,
+                          TransactionStatus::Authorized).await?;
+    let lifetime = env::var(HOLD_LIFETIME_VAR).ok().and_then(|val| val.parse().ok()).unwrap_or(DEFAULT_HOLD_LIFETIME);
+    conn.execute("update transaction set expires=$1 where id=$2",
+                 &[&(Utc::now() + Duration::seconds(lifetime)), &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    info!("authorization: {} is holding {} on account: {}", trans_id, amount, src_account_id);
+    Ok(trans_id)
+}
+
+pub async fn capture(conn: &Transaction<'_>, hold_id: i32, merch_id: i32, amount: Option<i32>, fee_account_id: i32,
+                     order: Order) -> Result<i32, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &TransactionType::CardCapture, &order).await? {
+        return Ok(trans_id);
+    }
+    let hold = get_hold(conn, hold_id, merch_id).await?;
+    let amount = amount.unwrap_or_else(|| hold.remaining());
+    if amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    if amount > hold.remaining() {
+        return Err(CaptureExceedsAuthorization);
+    }
+
+    // the captured part stops being held and gets posted by the capture transaction instead
+    account::lock_by_id(conn, hold.principal.src_acc_id).await?;
+    post_item(conn, amount as i64, hold.principal.src_acc_id, hold.principal.dest_acc_id, 0, -1).await?;
+    let trans_id = deposit(conn, hold.principal.src_acc_id, hold.principal.dest_acc_id, fee_account_id, amount,
+                           TransactionType::CardCapture, order).await?;
+    set_parent(conn, trans_id, hold.id).await?;
+    if amount == hold.remaining() {
+        set_status(conn, hold.id, TransactionStatus::Captured).await?;
+    }
+    Ok(trans_id)
+}
+
+pub async fn void(conn: &Transaction<'_>, hold_id: i32, merch_id: i32) -> Result<(), Errors> {
+    let hold = get_hold(conn, hold_id, merch_id).await?;
+    set_status(conn, hold.id, TransactionStatus::Voided).await
+}
+
+// the open authorization a clearing record refers to, matched by the approval code handed out for it
+pub async fn find_hold(conn: &Transaction<'_>, card_id: i32, auth_code: &str, merch_id: i32) -> Result<Option<Hold>, Errors> {
+    let id: i32 = match conn.query("select id from transaction where card_id=$1 and auth_code=$2 and type=$3 and status=$4 \
+     order by id for update",
+                                   &[&card_id, &auth_code, &TransactionType::CardAuthorization.to_db_val(),
+                                       &TransactionStatus::Authorized.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Ok(None); }
+        Some(row) => { row.get("id") }
+    };
+    get_hold(conn, id, merch_id).await.map(Some)
+}
+
+// the spend an auth code was given to once it can't be captured any more, because it was captured, voided,
+// expired or posted right away
+pub async fn find_closed_spend(conn: &Transaction<'_>, card_id: i32, auth_code: &str) -> Result<Option<i32>, Errors> {
+    let rows = conn.query("select id from transaction where card_id=$1 and auth_code=$2 and status <> all($3) \
+     order by id desc limit 1",
+                          &[&card_id, &auth_code, &vec![TransactionStatus::Pending.to_db_val(),
+                              TransactionStatus::Authorized.to_db_val(), TransactionStatus::Failed.to_db_val()]]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    Ok(rows.first().map(|row| row.get("id")))
+}
+
+// settles a spend the issuer has to honour without an authorization, so the balance isn't checked
+pub async fn force_post(conn: &Transaction<'_>, src_account_id: i32, dest_account_id: i32, amount: i32,
+                        order: Order) -> Result<i32, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &TransactionType::ForcePost, &order).await? {
+        return Ok(trans_id);
+    }
+    if amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    account::lock_by_id(conn, src_account_id).await?;
+    let trans_id = create(conn, src_account_id, dest_account_id, amount, &TransactionType::ForcePost, order,
+                          TransactionStatus::Completed).await?;
+    info!("{} was force posted from account: {} by transaction: {}", amount, src_account_id, trans_id);
+    Ok(trans_id)
+}
+
+async fn get_hold(conn: &Transaction<'_>, id: i32, merch_id: i32) -> Result<Hold, Errors> {
+    let status = match conn.query("select status from transaction where id=$1 and merch_id=$2 and type=$3 for update",
+                                  &[&id, &merch_id, &TransactionType::CardAuthorization.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { return Err(TransactionNotFound); }
+        Some(row) => { TransactionStatus::from_db_val(row.get("status")).unwrap() }
+    };
+    if status != TransactionStatus::Authorized {
+        return Err(InvalidStatusTransition(format!("authorization is already {}", status.to_db_val())));
+    }
+
+    match conn.query("select i.amount, i.src_acc_id, i.dest_acc_id, \
+     coalesce((select sum(c.amount) from transaction_item c join transaction t on t.id = c.trans_id \
+      where t.parent_id = i.trans_id and c.type = i.type), 0) as captured \
+     from transaction_item i where i.trans_id = $1 and i.type = $2",
+                     &[&id, &ItemType::Principal.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Err(TransactionNotFound) }
+        Some(row) => {
+            Ok(Hold {
+                id,
+                principal: Leg {
+                    src_acc_id: row.get("src_acc_id"),
+                    dest_acc_id: row.get("dest_acc_id"),
+                    amount: row.get("amount"),
+                },
+                captured: row.get::<_, i64>("captured") as i32,
+            })
+        }
+    }
+}
+
+// children belong to the same card and card merchant as their parent
+async fn set_parent(conn: &Transaction<'_>, trans_id: i32, parent_id: i32) -> Result<u64, Errors> {
+    conn.execute("update transaction t set parent_id = p.id, card_id = p.card_id, \
+     merchant_descriptor = p.merchant_descriptor, mcc = p.mcc, merchant_country = p.merchant_country \
+     from transaction p where p.id = $1 and t.id = $2", &[&parent_id, &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })
+}
+
+pub async fn set_card(conn: &Transaction<'_>, trans_id: i32, card_id: i32) -> Result<u64, Errors> {
+    conn.execute("update transaction set card_id=$1 where id=$2", &[&card_id, &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })
+}
+
+pub async fn set_merchant_data(conn: &Transaction<'_>, trans_id: i32, merchant: &MerchantData) -> Result<u64, Errors> {
+    conn.execute("update transaction set merchant_descriptor=$1, mcc=$2, merchant_country=$3 where id=$4",
+                 &[&merchant.descriptor, &merchant.mcc, &merchant.country, &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })
+}
+
+// approval code handed to the acquirer, clearing records refer back to the spend with it
+pub async fn set_auth_code(conn: &Transaction<'_>, trans_id: i32) -> Result<String, Errors> {
+    let auth_code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    conn.execute("update transaction set auth_code=$1 where id=$2", &[&auth_code, &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    Ok(auth_code)
+}
+
+pub async fn find_open_holds(conn: &Transaction<'_>, card_id: i32) -> Result<Vec<i32>, Errors> {
+    Ok(conn.query("select id from transaction where card_id=$1 and type=$2 and status=$3 order by id for update",
+                  &[&card_id, &TransactionType::CardAuthorization.to_db_val(),
+                      &TransactionStatus::Authorized.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.iter().map(|row| row.get("id")).collect())
+}
+
+// spend is what the card withdrew or still holds, a hold counts once no matter how many captures it had.
+// an open hold counts in full, a closed one for what was captured from it, whether it ended captured or voided
+pub async fn get_card_spend(conn: &Transaction<'_>, card_id: i32) -> Result<CardSpend, Errors> {
+    let rows = conn.query("select \
+     coalesce(sum(s.amount) filter (where s.created >= date_trunc('day', now())), 0) as daily, \
+     coalesce(sum(s.amount) filter (where s.created >= date_trunc('week', now())), 0) as weekly, \
+     coalesce(sum(s.amount) filter (where s.created >= date_trunc('month', now())), 0) as monthly, \
+     coalesce(sum(s.amount), 0) as lifetime, \
+     count(*) filter (where s.created >= date_trunc('day', now())) as daily_count, \
+     count(*) filter (where s.created >= date_trunc('week', now())) as weekly_count, \
+     count(*) filter (where s.created >= date_trunc('month', now())) as monthly_count \
+     from (select t.created, i.amount from transaction t join transaction_item i on i.trans_id = t.id and i.type = $2 \
+      where t.card_id = $1 and t.type in ($3, $4) and t.status = $5 \
+      union all \
+      select h.created, case when h.status = $7 then i.amount else c.captured end as amount \
+      from transaction h join transaction_item i on i.trans_id = h.id and i.type = $2 \
+      left join (select t.parent_id, sum(ci.amount)::integer as captured \
+       from transaction t join transaction_item ci on ci.trans_id = t.id and ci.type = $2 \
+       where t.card_id = $1 and t.type = $8 and t.status = $5 group by t.parent_id) c on c.parent_id = h.id \
+      where h.card_id = $1 and h.type = $6 and (h.status = $7 or c.captured is not null)) s",
+                          &[&card_id, &ItemType::Principal.to_db_val(),
+                              &TransactionType::VirtualCardWithdraw.to_db_val(),
+                              &TransactionType::ForcePost.to_db_val(), &TransactionStatus::Completed.to_db_val(),
+                              &TransactionType::CardAuthorization.to_db_val(),
+                              &TransactionStatus::Authorized.to_db_val(),
+                              &TransactionType::CardCapture.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let row = rows.first().unwrap();
+    Ok(CardSpend {
+        daily: row.get("daily"),
+        weekly: row.get("weekly"),
+        monthly: row.get("monthly"),
+        lifetime: row.get("lifetime"),
+        daily_count: row.get("daily_count"),
+        weekly_count: row.get("weekly_count"),
+        monthly_count: row.get("monthly_count"),
+    })
+}
+
+// moves whatever was loaded onto the card and is still in the pool account back to the funding account
+pub async fn sweep(conn: &Transaction<'_>, card_id: i32, pool_account_id: i32, dest_account_id: i32,
+                   order: Order) -> Result<Option<i32>, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &TransactionType::CardSweep, &order).await? {
+        return Ok(Some(trans_id));
+    }
+    account::lock_by_id(conn, pool_account_id).await?;
+    let loaded: i64 = conn.query("select coalesce(sum(case when i.dest_acc_id = $2 then i.amount else -i.amount end), 0) \
+     as amount from transaction_item i join transaction t on t.id = i.trans_id \
+     left join transaction p on p.id = t.parent_id \
+     where t.card_id = $1 and i.type = $3 and t.status in ($4, $5) \
+     and (t.type in ($6, $7) or p.type = $6)",
+                                 &[&card_id, &pool_account_id, &ItemType::Principal.to_db_val(),
+                                     &TransactionStatus::Completed.to_db_val(), &TransactionStatus::Reversed.to_db_val(),
+                                     &TransactionType::VirtualCardDeposit.to_db_val(),
+                                     &TransactionType::CardSweep.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().unwrap().get("amount");
+    if loaded <= 0 {
+        return Ok(None);
+    }
+
+    let trans_id = create(conn, pool_account_id, dest_account_id, loaded as i32, &TransactionType::CardSweep, order,
+                          TransactionStatus::Completed).await?;
+    set_card(conn, trans_id, card_id).await?;
+    info!("{} was swept from card: {} to account: {}", loaded, card_id, dest_account_id);
+    Ok(Some(trans_id))
+}
+
+pub async fn withdraw(conn: &Transaction<'_>, src_account_id: i32, dest_account_id: i32, fee_account_id: i32, amount: i32,
+                      trans_type: TransactionType, order: Order) -> Result<i32, Errors> {
+    if let Some(trans_id) = find_by_order(conn, &trans_type, &order).await? {
+        return Ok(trans_id);
+    }
+    if amount <= 0 {
+        return Err(InvalidAmount("amount must be positive".to_string()));
+    }
+    account::lock_by_id(conn, src_account_id).await?;
     let fee = calculate_fee(conn, amount, &trans_type, dest_account_id).await?;
 
     if get_sum(conn, src_account_id).await? - (amount as i64) - (fee as i64) < 0 {
-        return Err(TransactionError("source account does not have enough funds".to_string()));
+        return Err(InsufficientFunds);
     }
 
-    let trans_id = create(conn, src_account_id, dest_account_id, amount, &trans_type, order_id).await?;
+    let trans_id = create(conn, src_account_id, dest_account_id, amount, &trans_type, order,
+                          TransactionStatus::Completed).await?;
     if fee > 0 {
-        create_item(conn, fee, trans_id, src_account_id, fee_account_id).await?;
+        create_item(conn, fee, trans_id, src_account_id, fee_account_id, ItemType::Fee,
+                    TransactionStatus::Completed).await?;
     }
 
     info!("transaction with type: {} was created",trans_type.to_db_val());
@@ -112,71 +927,372 @@ pub async fn withdraw(conn: &DBConn, src_account_id: i32, dest_account_id: i32,
     Ok(trans_id)
 }
 
-async fn create(conn: &DBConn, src_account_id: i32, dest_account_id: i32, amount: i32,
-                trans_type: &TransactionType, order_id: String) -> Result<i32, Errors> {
+async fn create<C: GenericClient>(conn: &C, src_account_id: i32, dest_account_id: i32, amount: i32,
+                trans_type: &TransactionType, order: Order, status: TransactionStatus) -> Result<i32, Errors> {
     let src_account = account::get_active_by_id(conn, src_account_id).await?;
     let dest_account = account::get_active_by_id(conn, dest_account_id).await?;
 
     if src_account.currency != dest_account.currency {
-        return Err(
-            TransactionError("source account currency doesn't match destination account currency".to_string()));
+        return Err(CurrencyMismatch);
     }
 
+    let trans_id = match order.pending_id {
+        Some(trans_id) => {
+            set_status(conn, trans_id, status.clone()).await?;
+            trans_id
+        }
+        None => { insert(conn, trans_type, &order, &status).await? }
+    };
+    create_item(conn, amount, trans_id, src_account_id, dest_account_id, ItemType::Principal, status).await?;
+
+    Ok(trans_id)
+}
+
+async fn insert<C: GenericClient>(conn: &C, trans_type: &TransactionType, order: &Order,
+                                  status: &TransactionStatus) -> Result<i32, Errors> {
+    let (order_id, system_ref) = if order.system { (None, Some(&order.order_id)) } else { (Some(&order.order_id), None) };
     let trans_id: i32 = conn.query(
-        "insert into transaction (id,type,status,order_id) values (default,$1,$2,$3) returning id",
-        &[&trans_type.to_db_val(), &TransactionStatus::Completed.to_db_val(), &order_id]).await
+        "insert into transaction (id,type,status,order_id,system_ref,merch_id,request_hash,created) values (default,$1,$2,$3,$4,$5,$6,now()) returning id",
+        &[&trans_type.to_db_val(), &status.to_db_val(), &order_id, &system_ref,
+            &order.merch_id, &order.request_hash]).await
         .map_err(|e| {
-            TransactionError(e.to_string())
-        })?.get(0).unwrap().get("id");
+            if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                IdempotencyConflict("transaction with this orderId is already being processed".to_string())
+            } else {
+                InternalError(e.to_string())
+            }
+        })?.first().unwrap().get("id");
 
-    create_item(conn, amount, trans_id, src_account_id, dest_account_id).await?;
+    add_status_history(conn, trans_id, status).await?;
+    webhook::publish(conn, order.merch_id, "transaction.created", &TransactionEvent {
+        transaction_id: trans_id,
+        trans_type: trans_type.to_db_val(),
+        status: status.to_db_val(),
+        order_id: order_id.map(String::as_str),
+        system_ref: system_ref.map(String::as_str),
+    }).await?;
+    Ok(trans_id)
+}
 
+// a card spend is pending while it's checked and the merchant is asked, so one that gets declined or fails stays in the
+// history as failed. the order stays claimed until then
+pub async fn create_pending(conn: &mut DBConn, trans_type: &TransactionType, order: &Order, card_id: i32,
+                            merchant: &MerchantData) -> Result<i32, Errors> {
+    let tx = begin(conn).await?;
+    let trans_id = insert(&tx, trans_type, order, &TransactionStatus::Pending).await?;
+    tx.execute("update transaction set expires=$1 where id=$2",
+               &[&(Utc::now() + Duration::seconds(PENDING_LIFETIME)), &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    set_card(&tx, trans_id, card_id).await?;
+    set_merchant_data(&tx, trans_id, merchant).await?;
+    commit(tx).await?;
     Ok(trans_id)
 }
 
-async fn create_item(conn: &DBConn, amount: i32, trans_id: i32, src_account_id: i32, dest_acccount_id: i32) -> Result<u64, Errors> {
-    Ok(conn.execute(
-        "insert into transaction_item (id, amount, created, trans_id, src_acc_id, dest_acc_id) values(default, $1, now(), $2, $3, $4)",
-        &[&amount, &trans_id, &src_account_id, &dest_acccount_id]).await
+// a failed spend gives up its order, so the orderId can be used again
+pub async fn fail(conn: &mut DBConn, trans_id: i32) -> Result<(), Errors> {
+    let tx = begin(conn).await?;
+    set_status(&tx, trans_id, TransactionStatus::Failed).await?;
+    commit(tx).await
+}
+
+pub async fn set_status<C: GenericClient>(conn: &C, trans_id: i32, next: TransactionStatus) -> Result<(), Errors> {
+    let rows = conn.query("select type, status, order_id, system_ref, merch_id from transaction where id=$1 for update",
+                          &[&trans_id]).await
         .map_err(|e| {
-            TransactionError(e.to_string())
-        })?)
+            InternalError(e.to_string())
+        })?;
+    let row = rows.first().ok_or(TransactionNotFound)?;
+    let current = TransactionStatus::from_db_val(row.get("status")).unwrap();
+    if !current.can_transition_to(&next) {
+        return Err(InvalidStatusTransition(format!("transaction can't change status from {} to {}",
+                                                   current.to_db_val(), next.to_db_val())));
+    }
+
+    conn.execute("update transaction set status=$1 where id=$2", &[&next.to_db_val(), &trans_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    add_status_history(conn, trans_id, &next).await?;
+    webhook::publish(conn, row.get("merch_id"), "transaction.status_changed", &TransactionEvent {
+        transaction_id: trans_id,
+        trans_type: row.get("type"),
+        status: next.to_db_val(),
+        order_id: row.get("order_id"),
+        system_ref: row.get("system_ref"),
+    }).await?;
+
+    // settle or release the amounts that were held as pending while the transaction was open,
+    // whatever was already captured by child transactions has been released by the captures themselves
+    if current.is_open() && !next.is_open() {
+        let posted = if next.is_posted() { 1 } else { 0 };
+        for row in conn.query("select i.src_acc_id, i.dest_acc_id, i.amount - \
+         coalesce((select sum(c.amount) from transaction_item c join transaction t on t.id = c.trans_id \
+          where t.parent_id = i.trans_id and c.type = i.type and c.src_acc_id = i.src_acc_id \
+          and c.dest_acc_id = i.dest_acc_id), 0) as amount \
+         from transaction_item i where i.trans_id=$1", &[&trans_id]).await
+            .map_err(|e| {
+                InternalError(e.to_string())
+            })?.iter() {
+            let amount: i64 = row.get("amount");
+            post_item(conn, amount, row.get("src_acc_id"), row.get("dest_acc_id"), posted, -1).await?;
+        }
+    }
+    info!("transaction: {} changed status from {} to {}", trans_id, current.to_db_val(), next.to_db_val());
+    Ok(())
 }
 
-async fn get_sum(conn: &DBConn, account_id: i32) -> Result<i64, Errors> {
-    Ok(get_sum_by_dest_acc(conn, account_id).await? - get_sum_by_src_acc(conn, account_id).await?)
+pub async fn find_expired(conn: &Transaction<'_>) -> Result<Vec<i32>, Errors> {
+    Ok(conn.query("select id from transaction where status in ($1, $2) and expires < now() \
+     order by id for update skip locked",
+                  &[&TransactionStatus::Pending.to_db_val(), &TransactionStatus::Authorized.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.iter().map(|row| row.get("id")).collect())
 }
 
-async fn get_sum_by_src_acc(conn: &DBConn, account_id: i32) -> Result<i64, Errors> {
-    conn.query("select sum(amount) from transaction_item where src_acc_id=$1", &[&account_id]).await
+async fn add_status_history<C: GenericClient>(conn: &C, trans_id: i32, status: &TransactionStatus) -> Result<u64, Errors> {
+    conn.execute("insert into transaction_status_history (id, trans_id, status, created) values (default, $1, $2, now())",
+                 &[&trans_id, &status.to_db_val()]).await
         .map_err(|e| {
-            TransactionError(e.to_string())
-        })?.get(0).map(get_sum_from_row).unwrap()
+            InternalError(e.to_string())
+        })
 }
 
-async fn get_sum_by_dest_acc(conn: &DBConn, account_id: i32) -> Result<i64, Errors> {
-    conn.query("select sum(amount) from transaction_item where dest_acc_id=$1", &[&account_id]).await
+pub async fn find_by_order<C: GenericClient>(conn: &C, trans_type: &TransactionType, order: &Order) -> Result<Option<i32>, Errors> {
+    let query = if order.system {
+        "select id, type, status, request_hash from transaction where merch_id=$1 and system_ref=$2 and status<>$3"
+    } else {
+        "select id, type, status, request_hash from transaction where merch_id=$1 and order_id=$2 and status<>$3"
+    };
+    match conn.query(query, &[&order.merch_id, &order.order_id, &TransactionStatus::Failed.to_db_val()]).await
         .map_err(|e| {
-            TransactionError(e.to_string())
-        })?.get(0).map(get_sum_from_row).unwrap()
+            InternalError(e.to_string())
+        })?.first() {
+        None => { Ok(None) }
+        Some(row) => {
+            let existing_type: String = row.get("type");
+            let request_hash: String = row.get("request_hash");
+            if existing_type != trans_type.to_db_val() || request_hash != order.request_hash {
+                return Err(IdempotencyConflict("orderId was already used with different parameters".to_string()));
+            }
+            let trans_id: i32 = row.get("id");
+            if TransactionStatus::from_db_val(row.get("status")) == Some(TransactionStatus::Pending) {
+                if order.pending_id == Some(trans_id) {
+                    return Ok(None);
+                }
+                return Err(IdempotencyConflict("transaction with this orderId is already being processed".to_string()));
+            }
+            info!("transaction with {}: {} was replayed", order.column(), order.order_id);
+            Ok(Some(trans_id))
+        }
+    }
 }
 
-fn get_sum_from_row(row: &Row) -> Result<i64, Errors> {
-    let sum_opt: Option<i64>=row.get(0);
-    match sum_opt {
-        None => {Ok(0)}
-        Some(sum) => {Ok(sum)}
+async fn create_item<C: GenericClient>(conn: &C, amount: i32, trans_id: i32, src_account_id: i32, dest_acccount_id: i32,
+                                       item_type: ItemType, status: TransactionStatus) -> Result<u64, Errors> {
+    let res = conn.execute(
+        "insert into transaction_item (id, amount, created, trans_id, src_acc_id, dest_acc_id, type) values(default, $1, now(), $2, $3, $4, $5)",
+        &[&amount, &trans_id, &src_account_id, &dest_acccount_id, &item_type.to_db_val()]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    if status.is_posted() {
+        post_item(conn, amount as i64, src_account_id, dest_acccount_id, 1, 0).await?;
+    } else if status.is_open() {
+        post_item(conn, amount as i64, src_account_id, dest_acccount_id, 0, 1).await?;
     }
+    Ok(res)
+}
+
+// moves an item's amount into (factor 1) or out of (factor -1) the posted and pending balances of both accounts
+async fn post_item<C: GenericClient>(conn: &C, amount: i64, src_account_id: i32, dest_account_id: i32,
+                                     posted: i64, pending: i64) -> Result<(), Errors> {
+    // balance rows are always updated in account id order so concurrent postings can't deadlock on them
+    let mut postings = [(src_account_id, -amount * posted, amount * pending, 0),
+        (dest_account_id, amount * posted, 0, amount * pending)];
+    postings.sort_by_key(|(account_id, _, _, _)| *account_id);
+    for (account_id, delta, debits, credits) in postings.iter() {
+        update_balance(conn, *account_id, *delta, *debits, *credits).await?;
+    }
+    Ok(())
+}
+
+async fn update_balance<C: GenericClient>(conn: &C, account_id: i32, delta: i64, pending_debits: i64,
+                                          pending_credits: i64) -> Result<u64, Errors> {
+    conn.execute("insert into account_balance (acc_id, balance, pending_debits, pending_credits, updated) \
+     values ($1, $2, $3, $4, now()) on conflict (acc_id) do update set \
+     balance = account_balance.balance + excluded.balance, \
+     pending_debits = account_balance.pending_debits + excluded.pending_debits, \
+     pending_credits = account_balance.pending_credits + excluded.pending_credits, updated = now()",
+                 &[&account_id, &delta, &pending_debits, &pending_credits]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })
+}
+
+pub async fn get_balance<C: GenericClient>(conn: &C, account_id: i32) -> Result<Balance, Errors> {
+    Ok(conn.query("select balance, pending_debits, pending_credits from account_balance where acc_id=$1",
+                  &[&account_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?.first().map(|row| {
+        Balance {
+            posted: row.get("balance"),
+            pending_debits: row.get("pending_debits"),
+            pending_credits: row.get("pending_credits"),
+        }
+    }).unwrap_or(Balance {
+        posted: 0,
+        pending_debits: 0,
+        pending_credits: 0,
+    }))
+}
+
+pub async fn get_sum<C: GenericClient>(conn: &C, account_id: i32) -> Result<i64, Errors> {
+    Ok(get_balance(conn, account_id).await?.available())
+}
+
+pub async fn get_history<C: GenericClient>(conn: &C, account_id: i32, query: HistoryQuery) -> Result<HistoryResponse, Errors> {
+    find_history(conn, Some(account_id), None, query).await
+}
+
+// captures, voids, reversals and refunds inherit the card of their parent, so they are part of the card history too
+pub async fn get_card_history<C: GenericClient>(conn: &C, card_id: i32, query: HistoryQuery) -> Result<HistoryResponse, Errors> {
+    find_history(conn, None, Some(card_id), query).await
+}
+
+async fn find_history<C: GenericClient>(conn: &C, account_id: Option<i32>, card_id: Option<i32>,
+                                        query: HistoryQuery) -> Result<HistoryResponse, Errors> {
+    if let Some(trans_type) = &query.trans_type {
+        TransactionType::from_db_val(trans_type).ok_or_else(|| {
+            InvalidFilter(format!("type: {} is not valid", trans_type))
+        })?;
+    }
+    if let Some(status) = &query.status {
+        TransactionStatus::from_db_val(status).ok_or_else(|| {
+            InvalidFilter(format!("status: {} is not valid", status))
+        })?;
+    }
+    let before_id = match &query.cursor {
+        None => { None }
+        Some(cursor) => { Some(decode_cursor(cursor)?) }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(InvalidFilter(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+
+    // one extra row is fetched to find out whether there is a next page
+    let rows = conn.query("select t.id, t.type, t.status, t.order_id, t.system_ref, t.parent_id, t.card_id, t.merchant_descriptor, t.mcc, \
+     t.merchant_country, t.auth_code, t.created from transaction t \
+     where ($1::integer is null or exists (select 1 from transaction_item i where i.trans_id = t.id \
+     and (i.src_acc_id = $1 or i.dest_acc_id = $1))) and ($9::integer is null or t.card_id = $9) \
+     and ($2::timestamptz is null or t.created >= $2) and ($3::timestamptz is null or t.created < $3) \
+     and ($4::varchar is null or t.type = $4) and ($5::varchar is null or t.status = $5) \
+     and ($6::varchar is null or t.order_id = $6) and ($7::integer is null or t.id < $7) \
+     order by t.id desc limit $8",
+                          &[&account_id, &query.from, &query.to, &query.trans_type, &query.status, &query.order_id,
+                              &before_id, &(limit + 1), &card_id]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    let mut transactions = to_views(conn, &rows).await?;
+    let next_cursor = if transactions.len() as i64 > limit {
+        transactions.truncate(limit as usize);
+        transactions.last().map(|t| encode_cursor(t.id))
+    } else {
+        None
+    };
+    Ok(HistoryResponse {
+        transactions,
+        next_cursor,
+    })
+}
+
+async fn to_views<C: GenericClient>(conn: &C, rows: &[Row]) -> Result<Vec<TransactionView>, Errors> {
+    let ids: Vec<i32> = rows.iter().map(|row| row.get("id")).collect();
+    let item_rows = conn.query("select * from transaction_item where trans_id = any($1) order by id", &[&ids]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let status_rows = conn.query("select * from transaction_status_history where trans_id = any($1) order by id",
+                                 &[&ids]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+
+    Ok(rows.iter().map(|row| {
+        let id: i32 = row.get("id");
+        let items: Vec<ItemView> = item_rows.iter().filter(|item| item.get::<_, i32>("trans_id") == id)
+            .map(|item| {
+                ItemView {
+                    id: item.get("id"),
+                    item_type: item.get("type"),
+                    amount: item.get("amount"),
+                    src_acc_id: item.get("src_acc_id"),
+                    dest_acc_id: item.get("dest_acc_id"),
+                    created: item.get("created"),
+                }
+            }).collect();
+        let mut accounts: Vec<i32> = items.iter().flat_map(|item| vec![item.src_acc_id, item.dest_acc_id]).collect();
+        accounts.sort_unstable();
+        accounts.dedup();
+        TransactionView {
+            id,
+            trans_type: row.get("type"),
+            status: row.get("status"),
+            order_id: row.get("order_id"),
+            system_ref: row.get("system_ref"),
+            parent_id: row.get("parent_id"),
+            card_id: row.get("card_id"),
+            merchant_descriptor: row.get("merchant_descriptor"),
+            mcc: row.get("mcc"),
+            merchant_country: row.get("merchant_country"),
+            auth_code: row.get("auth_code"),
+            amount: sum_items(&items, ItemType::Principal),
+            fee: sum_items(&items, ItemType::Fee),
+            created: row.get("created"),
+            accounts,
+            items,
+            status_history: status_rows.iter().filter(|change| change.get::<_, i32>("trans_id") == id)
+                .map(|change| {
+                    StatusChange {
+                        status: change.get("status"),
+                        created: change.get("created"),
+                    }
+                }).collect(),
+        }
+    }).collect())
+}
+
+fn sum_items(items: &[ItemView], item_type: ItemType) -> i64 {
+    items.iter().filter(|item| item.item_type == item_type.to_db_val()).map(|item| item.amount as i64).sum()
+}
+
+pub fn encode_cursor(id: i32) -> String {
+    base64::encode_config(id.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<i32, Errors> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| InvalidFilter("cursor is not valid".to_string()))
 }
 
-async fn calculate_fee(conn: &DBConn, amount: i32, trans_type: &TransactionType, account_id: i32) -> Result<i32, Errors> {
+async fn calculate_fee<C: GenericClient>(conn: &C, amount: i32, trans_type: &TransactionType, account_id: i32) -> Result<i32, Errors> {
     conn.query("select rate from transaction_fee where type = $1 and acc_id = $2",
                &[&trans_type.to_db_val(), &account_id]).await
         .map_err(|e| {
-            TransactionError(e.to_string())
-        })?.get(0).map(|row| {
-        let rate: f32 = row.get("rate");
-        let amount_f: f32 = amount as f32;
+            InternalError(e.to_string())
+        })?.first().map(|row| {
+        let rate: f64 = row.get("rate");
+        let amount_f: f64 = amount as f64;
         let res: i32 = (rate * amount_f) as i32;
         Ok(res)
     }).unwrap_or(Ok(0))
@@ -0,0 +1,262 @@
+use std::env;
+use chrono::prelude::*;
+use serde::Serialize;
+use crate::error::Errors;
+use crate::error::Errors::InvalidFields;
+
+// customer fields are checked all at once so the response lists every field that is wrong, e.g.
+// {"field": "phone", "code": "invalid_phone", "detail": "phone must be in E.164 format, e.g. +14155550123"}
+const MIN_AGE_VAR: &str = "CUSTOMER_MIN_AGE";
+const DEFAULT_MIN_AGE: u32 = 18;
+
+pub const MAX_NAME_LENGTH: usize = 100;
+pub const MAX_ADDRESS_LENGTH: usize = 200;
+pub const MAX_CITY_LENGTH: usize = 100;
+pub const MAX_STATE_REGION_LENGTH: usize = 100;
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_EMAIL_LOCAL_LENGTH: usize = 64;
+const MAX_DOMAIN_LABEL_LENGTH: usize = 63;
+const MIN_PHONE_DIGITS: usize = 8;
+const MAX_PHONE_DIGITS: usize = 15;
+const MAX_POSTAL_CODE_LENGTH: usize = 10;
+const EMAIL_LOCAL_SYMBOLS: &str = "!#$%&'*+/=?^_`{|}~.-";
+
+const COUNTRIES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS", "BT", "BV", "BW",
+    "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN", "CO", "CR", "CU", "CV", "CW", "CX",
+    "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE", "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM",
+    "FO", "FR", "GA", "GB", "GD", "GE", "GF", "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU",
+    "GW", "GY", "HK", "HM", "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE",
+    "JM", "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC", "LI", "LK",
+    "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK", "ML", "MM", "MN", "MO", "MP",
+    "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA", "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP",
+    "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG", "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA",
+    "RE", "RO", "RS", "RU", "RW", "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO",
+    "SR", "SS", "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO", "TR",
+    "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI", "VN", "VU", "WF", "WS",
+    "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+// countries that don't use postal codes, an empty postalCode is accepted for them
+const WITHOUT_POSTAL_CODES: [&str; 59] = [
+    "AE", "AG", "AO", "AW", "BF", "BI", "BJ", "BO", "BS", "BW", "BZ", "CD", "CF", "CG", "CI", "CK", "CM", "DJ", "DM",
+    "ER", "FJ", "GA", "GD", "GH", "GM", "GQ", "GY", "HK", "JM", "KI", "KM", "KN", "KP", "LC", "ML", "MO", "MR", "MW",
+    "NR", "NU", "QA", "RW", "SB", "SC", "SL", "SR", "ST", "SY", "TD", "TF", "TG", "TK", "TL", "TO", "TV", "UG", "VU",
+    "YE", "ZW",
+];
+
+// 9 stands for a digit, A for a letter and anything else for itself, codes are compared upper case.
+// countries that aren't listed only get a generic check
+const POSTAL_CODE_FORMATS: [(&str, &[&str]); 35] = [
+    ("AT", &["9999"]),
+    ("AU", &["9999"]),
+    ("BE", &["9999"]),
+    ("BR", &["99999-999", "99999999"]),
+    ("CA", &["A9A 9A9", "A9A9A9"]),
+    ("CH", &["9999"]),
+    ("CN", &["999999"]),
+    ("CZ", &["999 99", "99999"]),
+    ("DE", &["99999"]),
+    ("DK", &["9999"]),
+    ("ES", &["99999"]),
+    ("FI", &["99999"]),
+    ("FR", &["99999"]),
+    ("GB", &["A9 9AA", "A99 9AA", "AA9 9AA", "AA99 9AA", "A9A 9AA", "AA9A 9AA"]),
+    ("GR", &["999 99", "99999"]),
+    ("HU", &["9999"]),
+    ("IN", &["999999"]),
+    ("IT", &["99999"]),
+    ("JP", &["999-9999", "9999999"]),
+    ("KR", &["99999"]),
+    ("LU", &["9999"]),
+    ("MX", &["99999"]),
+    ("NL", &["9999 AA", "9999AA"]),
+    ("NO", &["9999"]),
+    ("NZ", &["9999"]),
+    ("PL", &["99-999"]),
+    ("PT", &["9999-999"]),
+    ("RU", &["999999"]),
+    ("SE", &["999 99", "99999"]),
+    ("SG", &["999999"]),
+    ("SK", &["999 99", "99999"]),
+    ("TR", &["99999"]),
+    ("UA", &["99999"]),
+    ("US", &["99999", "99999-9999"]),
+    ("ZA", &["9999"]),
+];
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub detail: String,
+}
+
+// every check hands back the normalized value, values of fields that failed are only placeholders
+// and finish() turns the collected failures into an error
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn finish(self) -> Result<(), Errors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidFields(self.errors))
+        }
+    }
+
+    fn fail(&mut self, field: &'static str, code: &'static str, detail: String) {
+        self.errors.push(FieldError { field, code, detail });
+    }
+
+    pub fn text(&mut self, field: &'static str, value: &str, max_length: usize) -> String {
+        let value = value.trim();
+        if value.is_empty() {
+            self.fail(field, "required", format!("{} is required", field));
+        } else {
+            self.length(field, value, max_length);
+        }
+        value.to_string()
+    }
+
+    pub fn optional_text(&mut self, field: &'static str, value: &str, max_length: usize) -> Option<String> {
+        let value = value.trim();
+        self.length(field, value, max_length);
+        Some(value.to_string()).filter(|value| !value.is_empty())
+    }
+
+    fn length(&mut self, field: &'static str, value: &str, max_length: usize) {
+        if value.chars().count() > max_length {
+            self.fail(field, "too_long", format!("{} can't be longer than {} characters", field, max_length));
+        }
+    }
+
+    // the domain is lower cased, the local part is kept as given
+    pub fn email(&mut self, field: &'static str, value: &str) -> String {
+        let value = value.trim();
+        if value.is_empty() {
+            self.fail(field, "required", format!("{} is required", field));
+            return value.to_string();
+        }
+        if value.len() > MAX_EMAIL_LENGTH {
+            self.fail(field, "too_long", format!("{} can't be longer than {} characters", field, MAX_EMAIL_LENGTH));
+            return value.to_string();
+        }
+        match value.rsplit_once('@') {
+            Some((local, domain)) if valid_email_local(local) && valid_domain(domain) => {
+                format!("{}@{}", local, domain.to_ascii_lowercase())
+            }
+            _ => {
+                self.fail(field, "invalid_email", format!("{} is not a valid email address", field));
+                value.to_string()
+            }
+        }
+    }
+
+    // spaces, dashes, dots and parentheses are dropped and a leading 00 is read as +
+    pub fn phone(&mut self, field: &'static str, value: &str) -> String {
+        let compact: String = value.chars().filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')')).collect();
+        let digits = match compact.strip_prefix('+').or_else(|| compact.strip_prefix("00")) {
+            Some(digits) => { digits }
+            None => {
+                self.fail(field, "invalid_phone", format!("{} must start with + and the country calling code", field));
+                return value.trim().to_string();
+            }
+        };
+        if !digits.chars().all(|c| c.is_ascii_digit()) || digits.starts_with('0')
+            || !(MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits.len()) {
+            self.fail(field, "invalid_phone", format!("{} must be in E.164 format, e.g. +14155550123", field));
+            return value.trim().to_string();
+        }
+        format!("+{}", digits)
+    }
+
+    pub fn country(&mut self, field: &'static str, value: &str) -> String {
+        let value = value.trim().to_ascii_uppercase();
+        if !COUNTRIES.contains(&value.as_str()) {
+            self.fail(field, "invalid_country", format!("{} must be an ISO 3166 alpha-2 country code", field));
+        }
+        value
+    }
+
+    // the country is expected to be normalized already, postal codes of unknown countries aren't checked
+    pub fn postal_code(&mut self, field: &'static str, country: &str, value: &str) -> String {
+        let value = value.split_whitespace().collect::<Vec<&str>>().join(" ").to_ascii_uppercase();
+        if !COUNTRIES.contains(&country) {
+            return value;
+        }
+        if value.is_empty() {
+            if !WITHOUT_POSTAL_CODES.contains(&country) {
+                self.fail(field, "required", format!("{} is required in {}", field, country));
+            }
+            return value;
+        }
+        let valid = match POSTAL_CODE_FORMATS.iter().find(|(code, _)| *code == country) {
+            Some((_, formats)) => { formats.iter().any(|format| matches_format(&value, format)) }
+            None => {
+                value.len() <= MAX_POSTAL_CODE_LENGTH
+                    && value.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+            }
+        };
+        if !valid {
+            self.fail(field, "invalid_postal_code", format!("{} is not a valid postal code in {}", field, country));
+        }
+        value
+    }
+
+    // customers must be at least CUSTOMER_MIN_AGE years old on the given day
+    pub fn birth_date(&mut self, field: &'static str, value: &str, today: NaiveDate) -> NaiveDate {
+        let birth_date = match NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d") {
+            Ok(birth_date) => { birth_date }
+            Err(_) => {
+                self.fail(field, "invalid_date", format!("{} must be a date formatted as YYYY-MM-DD", field));
+                return today;
+            }
+        };
+        let min_age = env::var(MIN_AGE_VAR).ok().and_then(|val| val.parse().ok()).unwrap_or(DEFAULT_MIN_AGE);
+        if birth_date > today {
+            self.fail(field, "future_date", format!("{} can't be in the future", field));
+        } else if age(birth_date, today) < min_age {
+            self.fail(field, "under_minimum_age", format!("customer must be at least {} years old", min_age));
+        }
+        birth_date
+    }
+}
+
+fn age(birth_date: NaiveDate, today: NaiveDate) -> u32 {
+    let years = (today.year() - birth_date.year()) as u32;
+    if (today.month(), today.day()) < (birth_date.month(), birth_date.day()) {
+        years - 1
+    } else {
+        years
+    }
+}
+
+fn valid_email_local(local: &str) -> bool {
+    !local.is_empty() && local.len() <= MAX_EMAIL_LOCAL_LENGTH
+        && !local.starts_with('.') && !local.ends_with('.') && !local.contains("..")
+        && local.chars().all(|c| c.is_ascii_alphanumeric() || EMAIL_LOCAL_SYMBOLS.contains(c))
+}
+
+// at least two labels and a top level domain made of letters
+fn valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty() && label.len() <= MAX_DOMAIN_LABEL_LENGTH && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    labels.len() >= 2 && labels.iter().all(valid_label)
+        && labels.last().is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+fn matches_format(value: &str, format: &str) -> bool {
+    value.len() == format.len() && value.chars().zip(format.chars()).all(|(c, f)| match f {
+        '9' => { c.is_ascii_digit() }
+        'A' => { c.is_ascii_uppercase() }
+        _ => { c == f }
+    })
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use sha2::Sha256;
+use tokio_postgres::{GenericClient, Transaction};
+use crate::db::DBConn;
+use crate::error::Errors;
+use crate::error::Errors::InternalError;
+use crate::transaction;
+
+// keys are read from VAULT_KEYS, or from the file VAULT_KEY_FILE points to, one "<label>:<base64 key>" entry per line:
+// numbered labels are master key versions with the highest one used for wrapping, "fingerprint" keys the PAN fingerprints
+const KEYS_VAR: &str = "VAULT_KEYS";
+const KEY_FILE_VAR: &str = "VAULT_KEY_FILE";
+const FINGERPRINT_LABEL: &str = "fingerprint";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TOKEN_LENGTH: usize = 32;
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+struct Keyring {
+    current_version: i32,
+    master_keys: HashMap<i32, Vec<u8>>,
+    fingerprint_key: Vec<u8>,
+}
+
+pub struct Secret {
+    pub pan: String,
+    pub cvv: String,
+}
+
+// fails fast at startup instead of on the first card request
+pub fn init() {
+    keyring();
+}
+
+fn keyring() -> &'static Keyring {
+    KEYRING.get_or_init(|| {
+        let entries = match env::var(KEYS_VAR) {
+            Ok(keys) => { keys }
+            Err(_) => {
+                let path = env::var(KEY_FILE_VAR).expect("VAULT_KEYS or VAULT_KEY_FILE must be set");
+                fs::read_to_string(&path).unwrap_or_else(|e| panic!("vault key file {} can't be read: {}", path, e))
+            }
+        };
+        parse_keyring(&entries).unwrap_or_else(|e| panic!("vault keys are not valid: {}", e))
+    })
+}
+
+fn parse_keyring(entries: &str) -> Result<Keyring, String> {
+    let mut master_keys = HashMap::new();
+    let mut fingerprint_key = None;
+    for entry in entries.split(['\n', ',']).map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (label, encoded) = entry.split_once(':').ok_or(format!("entry {} has no label", entry))?;
+        let key = base64::decode(encoded.trim()).map_err(|e| e.to_string())?;
+        if key.len() != KEY_LENGTH {
+            return Err(format!("key {} must be {} bytes long", label, KEY_LENGTH));
+        }
+        if label == FINGERPRINT_LABEL {
+            fingerprint_key = Some(key);
+        } else {
+            master_keys.insert(label.parse().map_err(|_| format!("{} is not a key version", label))?, key);
+        }
+    }
+    Ok(Keyring {
+        current_version: *master_keys.keys().max().ok_or("no master key")?,
+        master_keys,
+        fingerprint_key: fingerprint_key.ok_or("no fingerprint key")?,
+    })
+}
+
+// a keyed hash, so the PAN can't be recovered by hashing every number in a BIN range
+pub fn fingerprint(pan: &str) -> String {
+    let mut mac: Hmac<Sha256> = Hmac::new_from_slice(&keyring().fingerprint_key).unwrap();
+    mac.update(pan.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+// every card gets its own data key, only the data key wrapped by the current master key is stored next to the ciphertexts
+pub async fn store<C: GenericClient>(conn: &C, secret: &Secret) -> Result<String, Errors> {
+    let keyring = keyring();
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect();
+    let data_key: [u8; KEY_LENGTH] = rand::thread_rng().gen();
+    let wrapped_key = encrypt(&keyring.master_keys[&keyring.current_version], &data_key, token.as_bytes())?;
+    let pan = encrypt(&data_key, secret.pan.as_bytes(), token.as_bytes())?;
+    let cvv = encrypt(&data_key, secret.cvv.as_bytes(), token.as_bytes())?;
+
+    conn.execute("insert into card_vault (token, key_version, wrapped_key, pan, cvv, created) \
+     values ($1, $2, $3, $4, $5, now())", &[&token, &keyring.current_version, &wrapped_key, &pan, &cvv]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    Ok(token)
+}
+
+// details can be revealed once, None means they already were
+pub async fn reveal(conn: &Transaction<'_>, token: &str) -> Result<Option<Secret>, Errors> {
+    let rows = conn.query("update card_vault set revealed = now() where token = $1 and revealed is null \
+     returning key_version, wrapped_key, pan, cvv", &[&token]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    let row = match rows.first() {
+        None => { return Ok(None); }
+        Some(row) => { row }
+    };
+    let data_key = unwrap_key(row.get("key_version"), row.get("wrapped_key"), token)?;
+    Ok(Some(Secret {
+        pan: decrypt_string(&data_key, row.get("pan"), token)?,
+        cvv: decrypt_string(&data_key, row.get("cvv"), token)?,
+    }))
+}
+
+// re-wraps the data keys of older master key versions with the current one, the ciphertexts stay as they are
+pub async fn rotate(conn: &mut DBConn) -> Result<usize, Errors> {
+    let keyring = keyring();
+    let tx = transaction::begin(conn).await?;
+    let rows = tx.query("select token, key_version, wrapped_key from card_vault where key_version <> $1 for update",
+                        &[&keyring.current_version]).await
+        .map_err(|e| {
+            InternalError(e.to_string())
+        })?;
+    for row in rows.iter() {
+        let token: &str = row.get("token");
+        let data_key = unwrap_key(row.get("key_version"), row.get("wrapped_key"), token)?;
+        let wrapped_key = encrypt(&keyring.master_keys[&keyring.current_version], &data_key, token.as_bytes())?;
+        tx.execute("update card_vault set key_version = $1, wrapped_key = $2 where token = $3",
+                   &[&keyring.current_version, &wrapped_key, &token]).await
+            .map_err(|e| {
+                InternalError(e.to_string())
+            })?;
+    }
+    transaction::commit(tx).await?;
+    info!("{} data keys were re-wrapped with master key version: {}", rows.len(), keyring.current_version);
+    Ok(rows.len())
+}
+
+fn unwrap_key(version: i32, wrapped_key: &str, token: &str) -> Result<Vec<u8>, Errors> {
+    let master_key = keyring().master_keys.get(&version).ok_or_else(|| {
+        InternalError(format!("master key version: {} is not loaded", version))
+    })?;
+    decrypt(master_key, wrapped_key, token.as_bytes())
+}
+
+// ciphertexts are stored as base64 of nonce followed by the sealed data, the vault token is the associated data
+fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<String, Errors> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad }).map_err(|e| {
+        InternalError(e.to_string())
+    })?);
+    Ok(base64::encode(sealed))
+}
+
+fn decrypt(key: &[u8], sealed: &str, aad: &[u8]) -> Result<Vec<u8>, Errors> {
+    let sealed = base64::decode(sealed).map_err(|e| {
+        InternalError(e.to_string())
+    })?;
+    if sealed.len() < NONCE_LENGTH {
+        return Err(InternalError("vault ciphertext is truncated".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).map_err(|e| {
+        InternalError(e.to_string())
+    })
+}
+
+fn decrypt_string(key: &[u8], sealed: &str, token: &str) -> Result<String, Errors> {
+    String::from_utf8(decrypt(key, sealed, token.as_bytes())?).map_err(|e| {
+        InternalError(e.to_string())
+    })
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn parallel_card_spends_never_overdraw_the_account() {
+    let server = Server::start();
+    let merchant = create_merchant("Parallel spends").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+
+    let spends: Vec<_> = (0..20).map(|_| {
+        let api = api.clone();
+        tokio::spawn(async move { api.post("/api/card/withdraw", spend(card_id, 100)).await })
+    }).collect();
+    let mut results = Vec::new();
+    for spend in spends {
+        results.push(spend.await.unwrap());
+    }
+
+    let succeeded = results.iter().filter(|(status, _)| *status == 200).count();
+    for (status, body) in results.iter().filter(|(status, _)| *status != 200) {
+        assert_eq!(*status, 422, "unexpected failure: {}", body);
+        assert_eq!(body["code"], "insufficient_funds");
+    }
+    assert_eq!(succeeded, 10);
+    let (status, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    assert_eq!(status, 200);
+    assert_eq!(balance["available"], 0);
+    assert_eq!(balance["posted"], 0);
+}
+
+#[tokio::test]
+async fn zero_and_negative_amounts_are_rejected() {
+    let server = Server::start();
+    let merchant = create_merchant("Invalid amounts").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+
+    for amount in [0, -100] {
+        let requests = [
+            ("/api/account/fund", json!({"accountId": merchant.account_id, "amount": amount, "orderId": order_id("fund")})),
+            ("/api/card/deposit", json!({"cardId": card_id, "amount": amount, "orderId": order_id("deposit")})),
+            ("/api/card/withdraw", spend(card_id, amount)),
+            ("/api/card/authorize", spend(card_id, amount)),
+        ];
+        for (path, request) in requests {
+            let (status, body) = api.post(path, request).await;
+            assert_eq!(status, 400, "{} accepted amount {}: {}", path, amount, body);
+            assert_eq!(body["code"], "invalid_amount");
+        }
+    }
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    assert_eq!(balance["available"], 1000);
+}
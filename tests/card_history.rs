@@ -0,0 +1,38 @@
+mod common;
+
+use serde_json::{json, Value};
+use common::*;
+
+fn ids(history: &Value) -> Vec<i64> {
+    history["transactions"].as_array().unwrap().iter().map(|t| t["id"].as_i64().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn card_history_only_lists_the_cards_own_transactions() {
+    let server = Server::start();
+    let merchant = create_merchant("Card history").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let customer_id = create_customer(&api).await;
+    let card_id = create_card(&api, &merchant, customer_id).await;
+    let sibling_id = create_card(&api, &merchant, customer_id).await;
+
+    let (_, spent) = api.post("/api/card/withdraw", spend(card_id, 100)).await;
+    let (_, authorized) = api.post("/api/card/authorize", spend(card_id, 50)).await;
+    let auth_id = authorized["trans_id"].as_i64().unwrap();
+    let (_, captured) = api.post(&format!("/api/card/authorization/{}/capture", auth_id),
+                                 json!({"amount": 20, "orderId": order_id("capture")})).await;
+    let (_, sibling_spent) = api.post("/api/card/withdraw", spend(sibling_id, 10)).await;
+
+    let (status, history) = api.get(&format!("/api/card/{}/transactions", card_id)).await;
+    assert_eq!(status, 200);
+    assert_eq!(ids(&history), vec![captured["trans_id"].as_i64().unwrap(), auth_id,
+                                   spent["trans_id"].as_i64().unwrap()]);
+
+    let (_, history) = api.get(&format!("/api/card/{}/transactions", sibling_id)).await;
+    assert_eq!(ids(&history), vec![sibling_spent["trans_id"].as_i64().unwrap()]);
+
+    // the account still sees everything, the funding included
+    let (_, history) = api.get(&format!("/api/account/{}/transactions", merchant.account_id)).await;
+    assert_eq!(ids(&history).len(), 5);
+}
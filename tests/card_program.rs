@@ -0,0 +1,33 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn programs_that_cant_issue_cards_are_refused() {
+    let server = Server::start();
+    let merchant = create_merchant("Card program").await;
+    let api = server.login(&merchant).await;
+    let db = db().await;
+    let insert = "insert into card_program (name, bin_from, bin_to, pan_length, expiry_months, merch_id) \
+     values ('Broken', $1, $2, $3, 36, $4) returning id";
+
+    for (bin_from, bin_to) in [(70_000_200i64, 70_000_100i64), (0, 70_000_100), (-70_000_100, -70_000_000),
+                               (9_999_990, 10_000_010)] {
+        let saved = db.query_one(insert, &[&bin_from, &bin_to, &16, &merchant.id]).await;
+        assert!(saved.is_err(), "a program with bin range {}..{} was saved", bin_from, bin_to);
+    }
+    for pan_length in [0, 11, 20] {
+        let saved = db.query_one(insert, &[&70_000_100i64, &70_000_200i64, &pan_length, &merchant.id]).await;
+        assert!(saved.is_err(), "a program with pan length {} was saved", pan_length);
+    }
+
+    // a bin as long as the pan leaves no room for the check digit
+    let program_id: i32 = db.query_one(insert, &[&100_000_000_000i64, &100_000_000_099i64, &12, &merchant.id])
+        .await.unwrap().get(0);
+    let (status, problem) = api.post("/api/card", json!({
+        "customerId": create_customer(&api).await, "accountId": merchant.account_id, "programId": program_id
+    })).await;
+    assert_eq!(status, 500, "{}", problem);
+    assert_eq!(problem["code"], "internal_error");
+}
@@ -0,0 +1,25 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn cards_of_a_deactivated_customer_cant_be_unfrozen() {
+    let server = Server::start();
+    let merchant = create_merchant("Card status").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 100).await;
+    let customer_id = create_customer(&api).await;
+    let card_id = create_card(&api, &merchant, customer_id).await;
+
+    let (status, body) = api.post(&format!("/api/customer/{}/deactivate", customer_id), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["frozen_cards"], json!([card_id]));
+
+    let (status, problem) = api.post(&format!("/api/card/{}/unfreeze", card_id), json!({})).await;
+    assert_eq!(status, 422, "{}", problem);
+    assert_eq!(problem["code"], "customer_not_active");
+    let (status, problem) = api.post("/api/card/withdraw", spend(card_id, 10)).await;
+    assert_eq!(status, 422, "{}", problem);
+    assert_eq!(problem["code"], "card_not_active");
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use serde_json::{json, Value};
+use common::*;
+
+async fn pan(api: &Api, card_id: i32) -> String {
+    let (status, revealed) = api.post(&format!("/api/card/{}/reveal", card_id), json!({})).await;
+    assert_eq!(status, 200, "{}", revealed);
+    revealed["pan"].as_str().unwrap().to_string()
+}
+
+async fn auth_code(api: &Api, trans_id: &Value) -> String {
+    let (_, transaction) = api.get(&format!("/api/transaction/{}", trans_id)).await;
+    transaction["auth_code"].as_str().unwrap().to_string()
+}
+
+async fn hold(api: &Api, card_id: i32, amount: i32) -> String {
+    let (status, body) = api.post("/api/card/authorize", spend(card_id, amount)).await;
+    assert_eq!(status, 200, "{}", body);
+    auth_code(api, &body["trans_id"]).await
+}
+
+fn record(pan: &str, auth_code: &str, amount: i32) -> String {
+    format!("{},{},{},{},5541,US,SHELL 123, NEW YORK\n", order_id("clearing"), pan, auth_code, amount)
+}
+
+fn kinds(report: &Value) -> Vec<&str> {
+    report["exceptions"].as_array().unwrap().iter().map(|exception| exception["kind"].as_str().unwrap()).collect()
+}
+
+async fn available(api: &Api, merchant: &Merchant) -> i64 {
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    balance["available"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn records_capture_their_holds_and_a_second_import_is_only_duplicates() {
+    let server = Server::start();
+    let merchant = create_merchant("Clearing").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let pan = pan(&api, card_id).await;
+
+    let file = format!("reference,pan,auth_code,amount,mcc,country,merchant_descriptor\n{}{}{}{}",
+                       record(&pan, &hold(&api, card_id, 100).await, 100),
+                       record(&pan, &hold(&api, card_id, 100).await, 60),
+                       record(&pan, &hold(&api, card_id, 100).await, 150),
+                       record(&pan, "", 20));
+    let (status, report) = api.post_text("/api/clearing", &file).await;
+    assert_eq!(status, 200, "{}", report);
+    assert_eq!((report["records"].as_i64(), report["captured"].as_i64(), report["force_posted"].as_i64(),
+                report["failed"].as_i64()), (Some(4), Some(3), Some(1), Some(0)), "{}", report);
+    // the partial capture releases 40, the excess of 50 is force posted on top
+    assert_eq!(kinds(&report), vec!["amount_mismatch", "amount_mismatch", "unmatched"]);
+    assert_eq!(available(&api, &merchant).await, 1000 - 100 - 60 - 150 - 20);
+
+    let (status, report) = api.post_text("/api/clearing", &file).await;
+    assert_eq!(status, 200, "{}", report);
+    assert_eq!(report["duplicates"], 4, "{}", report);
+    assert!(kinds(&report).is_empty(), "{}", report);
+    assert_eq!(available(&api, &merchant).await, 1000 - 100 - 60 - 150 - 20);
+}
+
+#[tokio::test]
+async fn records_for_spends_that_are_already_settled_are_not_posted_again() {
+    let server = Server::start();
+    let merchant = create_merchant("Clearing settled").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let pan = pan(&api, card_id).await;
+
+    let (_, voided) = api.post("/api/card/authorize", spend(card_id, 100)).await;
+    let (status, body) = api.post(&format!("/api/card/authorization/{}/void", voided["trans_id"]), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, captured) = api.post("/api/card/authorize", spend(card_id, 100)).await;
+    let (status, body) = api.post(&format!("/api/card/authorization/{}/capture", captured["trans_id"]),
+                                  json!({"amount": 100, "orderId": order_id("capture")})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, withdrawn) = api.post("/api/card/withdraw", spend(card_id, 100)).await;
+    assert_eq!(available(&api, &merchant).await, 800);
+
+    let file = format!("{}{}{}", record(&pan, &auth_code(&api, &voided["trans_id"]).await, 100),
+                       record(&pan, &auth_code(&api, &captured["trans_id"]).await, 100),
+                       record(&pan, &auth_code(&api, &withdrawn["trans_id"]).await, 100));
+    let (status, report) = api.post_text("/api/clearing", &file).await;
+    assert_eq!(status, 200, "{}", report);
+    assert_eq!((report["force_posted"].as_i64(), report["failed"].as_i64()), (Some(0), Some(3)), "{}", report);
+    assert_eq!(kinds(&report), vec!["already_settled"; 3]);
+    assert_eq!(available(&api, &merchant).await, 800);
+}
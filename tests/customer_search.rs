@@ -0,0 +1,25 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn phone_filter_matches_however_the_number_is_written() {
+    let server = Server::start();
+    let merchant = create_merchant("Customer search").await;
+    let api = server.login(&merchant).await;
+    let customer_id = create_customer_with(&api, json!({"phone": "+1 (415) 555-0177"})).await;
+
+    // "+1 415-555-0177", "00 1 415 555 0177" and "+14155550177"
+    for phone in ["%2B1%20415-555-0177", "00%201%20415%20555%200177", "%2B14155550177"] {
+        let (status, found) = api.get(&format!("/api/customers?phone={}", phone)).await;
+        assert_eq!(status, 200, "{}", found);
+        let ids: Vec<i64> = found["customers"].as_array().unwrap().iter()
+            .map(|customer| customer["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![customer_id as i64], "{} didn't find the customer", phone);
+    }
+
+    let (status, problem) = api.get("/api/customers?phone=4155550177").await;
+    assert_eq!(status, 400, "{}", problem);
+    assert_eq!(problem["code"], "invalid_fields");
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use warp::Filter;
+use common::*;
+
+struct Call {
+    signature: String,
+    body: Vec<u8>,
+    card_unlocked: bool,
+}
+
+// a merchant endpoint that answers every request with the current answer and records what it was sent
+struct Stub {
+    url: String,
+    answer: Arc<Mutex<Value>>,
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+async fn start_stub() -> Stub {
+    let answer = Arc::new(Mutex::new(json!({"decision": "approve"})));
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let (stub_answer, stub_calls) = (answer.clone(), calls.clone());
+    let route = warp::post().and(warp::header::<String>("Webhook-Signature")).and(warp::body::bytes())
+        .and_then(move |signature: String, body: hyper::body::Bytes| {
+            let (answer, calls) = (stub_answer.clone(), stub_calls.clone());
+            async move {
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                // the spend must not hold the card while the merchant is asked
+                let card_unlocked = db().await.query("select id from card where id = $1 for update nowait",
+                                                     &[&(request["cardId"].as_i64().unwrap() as i32)]).await.is_ok();
+                calls.lock().unwrap().push(Call { signature, body: body.to_vec(), card_unlocked });
+                let answer = answer.lock().unwrap().clone();
+                Ok::<_, Infallible>(warp::reply::json(&answer))
+            }
+        });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    Stub { url: format!("http://{}/decide", addr), answer, calls }
+}
+
+fn verify(secret: &str, call: &Call) -> bool {
+    let (timestamp, signature) = call.signature.strip_prefix("t=").and_then(|rest| rest.split_once(",v1=")).unwrap();
+    let mut mac: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(&call.body);
+    base64::encode(mac.finalize().into_bytes()) == signature
+}
+
+#[tokio::test]
+async fn only_https_urls_on_public_hosts_and_own_accounts_are_saved() {
+    let server = Server::start();
+    let merchant = create_merchant("Decision config").await;
+    let api = server.login(&merchant).await;
+    let config = |url: &str, funding_account_id: Option<i32>| json!({
+        "url": url, "timeoutMs": 500, "defaultDecision": "decline", "fundingAccountId": funding_account_id
+    });
+
+    for url in ["http://example.com/decide", "https://localhost/decide", "https://127.0.0.1/decide",
+        "https://10.1.2.3/decide", "https://192.168.0.10/decide", "https://169.254.169.254/latest",
+        "https://[::1]/decide", "https://[fd00::1]/decide", "https://intranet/decide"] {
+        let (status, problem) = api.put("/api/merchant/authorization-webhook", config(url, None)).await;
+        assert_eq!(status, 400, "{} was saved", url);
+        assert_eq!(problem["code"], "invalid_decision_config");
+    }
+    for system_account_id in [1, 2, 3] {
+        let (status, problem) = api.put("/api/merchant/authorization-webhook",
+                                        config("https://example.com/decide", Some(system_account_id))).await;
+        assert_eq!(status, 400, "system account {} was accepted", system_account_id);
+        assert_eq!(problem["code"], "invalid_decision_config");
+    }
+
+    let (status, saved) = api.put("/api/merchant/authorization-webhook",
+                                  config("https://example.com/decide", Some(merchant.account_id))).await;
+    assert_eq!(status, 200, "{}", saved);
+    let secret = saved["secret"].as_str().unwrap().to_string();
+    let (_, saved) = api.put("/api/merchant/authorization-webhook", config("https://example.com/other", None)).await;
+    assert_eq!(saved["secret"], secret.as_str());
+}
+
+#[tokio::test]
+async fn merchants_are_asked_with_a_signed_request_and_no_locks_held() {
+    let server = Server::start_with(&[("AUTHORIZATION_WEBHOOK_ALLOW_LOCAL", "true")]);
+    let merchant = create_merchant("Decision stub").await;
+    let api = server.login(&merchant).await;
+    let funding_account_id = create_account(&db().await, merchant.id).await;
+    fund(&api, funding_account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let stub = start_stub().await;
+    let (status, saved) = api.put("/api/merchant/authorization-webhook", json!({
+        "url": stub.url, "timeoutMs": 2000, "defaultDecision": "decline", "fundingAccountId": funding_account_id
+    })).await;
+    assert_eq!(status, 200, "{}", saved);
+    let secret = saved["secret"].as_str().unwrap();
+
+    *stub.answer.lock().unwrap() = json!({"decision": "approve", "fundingAmount": 300});
+    let spent = spend(card_id, 200);
+    let (status, body) = api.post("/api/card/withdraw", spent.clone()).await;
+    assert_eq!(status, 200, "{}", body);
+    {
+        let calls = stub.calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert!(verify(secret, &calls[0]), "signature doesn't match");
+        assert!(calls[0].card_unlocked, "the card was locked while the merchant was asked");
+        let request: Value = serde_json::from_slice(&calls[0].body).unwrap();
+        assert_eq!(request["type"], "withdraw");
+        assert_eq!(request["amount"], 200);
+    }
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    assert_eq!(balance["available"], 100);
+
+    // a replayed spend is answered without asking again
+    let (_, replayed) = api.post("/api/card/withdraw", spent).await;
+    assert_eq!(replayed["trans_id"], body["trans_id"]);
+    assert_eq!(stub.calls.lock().unwrap().len(), 1);
+
+    *stub.answer.lock().unwrap() = json!({"decision": "decline", "reason": "not today"});
+    let (status, problem) = api.post("/api/card/authorize", spend(card_id, 50)).await;
+    assert_eq!(status, 422);
+    assert_eq!(problem["code"], "authorization_declined");
+    assert_eq!(problem["detail"], "not today");
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    assert_eq!(balance["available"], 100);
+}
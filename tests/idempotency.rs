@@ -0,0 +1,64 @@
+mod common;
+
+use hyper::Method;
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn parallel_requests_with_one_key_create_one_customer() {
+    let server = Server::start();
+    let merchant = create_merchant("Parallel idempotency").await;
+    let api = server.login(&merchant).await;
+    let request = json!({
+        "phone": "+14155550100", "email": "jane@example.com", "firstName": "Jane", "lastName": "Doe",
+        "birthDate": "1990-01-01", "address": "1 Main St", "city": "Springfield", "stateRegion": "IL",
+        "country": "US", "postalCode": "62701"
+    });
+
+    let creates: Vec<_> = (0..10).map(|_| {
+        let (api, request) = (api.clone(), request.clone());
+        tokio::spawn(async move {
+            api.call_with(Method::POST, "/api/customer", Some(request), &[("Idempotency-Key", "parallel")]).await
+        })
+    }).collect();
+    let mut created = Vec::new();
+    for create in creates {
+        let (status, body) = create.await.unwrap();
+        match status {
+            200 => { created.push(body["customer_id"].clone()); }
+            _ => {
+                assert_eq!(status, 409, "unexpected failure: {}", body);
+                assert_eq!(body["code"], "idempotency_conflict");
+            }
+        }
+    }
+    assert!(!created.is_empty());
+    assert!(created.iter().all(|id| *id == created[0]), "more than one customer was created: {:?}", created);
+
+    let (status, replayed) = api.call_with(Method::POST, "/api/customer", Some(request),
+                                           &[("Idempotency-Key", "parallel")]).await;
+    assert_eq!(status, 200);
+    assert_eq!(replayed["customer_id"], created[0]);
+    let count: i64 = db().await.query_one("select count(*) from customer where merch_id = $1", &[&merchant.id])
+        .await.unwrap().get(0);
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn card_deposits_replay_after_the_card_is_frozen() {
+    let server = Server::start();
+    let merchant = create_merchant("Deposit replay").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 100).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let deposit = json!({"cardId": card_id, "amount": 50, "orderId": order_id("deposit")});
+
+    let (status, deposited) = api.post("/api/card/deposit", deposit.clone()).await;
+    assert_eq!(status, 200, "{}", deposited);
+    let (status, body) = api.post(&format!("/api/card/{}/freeze", card_id), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, replayed) = api.post("/api/card/deposit", deposit).await;
+    assert_eq!(status, 200, "{}", replayed);
+    assert_eq!(replayed["trans_id"], deposited["trans_id"]);
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use serde_json::{json, Value};
+use common::*;
+
+struct IsoCard {
+    id: i32,
+    pan: String,
+    // YYMM as field 14 carries it
+    expiry: String,
+}
+
+async fn iso_card(api: &Api, merchant: &Merchant) -> IsoCard {
+    let id = create_card(api, merchant, create_customer(api).await).await;
+    let (status, revealed) = api.post(&format!("/api/card/{}/reveal", id), json!({})).await;
+    assert_eq!(status, 200, "{}", revealed);
+    let (month, year) = revealed["expiry"].as_str().unwrap().split_once('/').unwrap();
+    IsoCard { id, pan: revealed["pan"].as_str().unwrap().to_string(), expiry: format!("{}{}", year, month) }
+}
+
+fn message(mti: &str, card: &IsoCard, amount: i32, rrn: &str) -> Value {
+    json!({"mti": mti, "fields": {
+        "2": card.pan, "4": format!("{:012}", amount), "14": card.expiry, "18": "5541", "37": rrn,
+        "43": "SHELL 123                NEW YORK     US"
+    }})
+}
+
+fn rrn() -> String {
+    order_id("").trim_start_matches('-').chars().take(12).collect()
+}
+
+async fn available(api: &Api, merchant: &Merchant) -> i64 {
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    balance["available"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn authorizations_and_financial_messages_are_approved_and_reversed() {
+    let server = Server::start();
+    let merchant = create_merchant("ISO 8583").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card = iso_card(&api, &merchant).await;
+
+    let hold_rrn = rrn();
+    let (status, answer) = api.post("/api/iso8583", message("0100", &card, 300, &hold_rrn)).await;
+    assert_eq!(status, 200);
+    assert_eq!(answer["mti"], "0110");
+    assert_eq!(answer["fields"]["39"], "00");
+    assert_eq!(answer["fields"]["38"].as_str().unwrap().len(), 6);
+    assert_ne!(answer["fields"]["2"], card.pan.as_str(), "the pan was echoed unmasked");
+    assert_eq!(available(&api, &merchant).await, 700);
+
+    let (_, answer) = api.post("/api/iso8583", message("0200", &card, 200, &rrn())).await;
+    assert_eq!(answer["mti"], "0210");
+    assert_eq!(answer["fields"]["39"], "00");
+    assert_eq!(available(&api, &merchant).await, 500);
+
+    // the reversal voids the hold, repeating it is approved again
+    for _ in 0..2 {
+        let (_, answer) = api.post("/api/iso8583", message("0400", &card, 300, &hold_rrn)).await;
+        assert_eq!(answer["mti"], "0410");
+        assert_eq!(answer["fields"]["39"], "00");
+    }
+    assert_eq!(available(&api, &merchant).await, 800);
+}
+
+#[tokio::test]
+async fn declines_are_answered_with_their_response_codes() {
+    let server = Server::start();
+    let merchant = create_merchant("ISO 8583 declines").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 100).await;
+    let card = iso_card(&api, &merchant).await;
+
+    let (_, answer) = api.post("/api/iso8583", message("0100", &card, 500, &rrn())).await;
+    assert_eq!(answer["fields"]["39"], "51");
+
+    let expired = IsoCard { id: card.id, pan: card.pan.clone(), expiry: "2001".to_string() };
+    let (_, answer) = api.post("/api/iso8583", message("0200", &expired, 50, &rrn())).await;
+    assert_eq!(answer["fields"]["39"], "54");
+
+    let (status, body) = api.post(&format!("/api/card/{}/freeze", card.id), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, answer) = api.post("/api/iso8583", message("0100", &card, 50, &rrn())).await;
+    assert_eq!(answer["fields"]["39"], "62");
+    assert_eq!(available(&api, &merchant).await, 100);
+}
+
+#[tokio::test]
+async fn malformed_pans_get_a_format_error() {
+    let server = Server::start();
+    let merchant = create_merchant("ISO 8583 format").await;
+    let api = server.login(&merchant).await;
+
+    let (status, answer) = api.post("/api/iso8583", json!({"mti": "0100", "fields": {"2": "123"}})).await;
+    assert_eq!(status, 200, "{}", answer);
+    assert_eq!(answer["mti"], "0110");
+    assert_eq!(answer["fields"]["39"], "30");
+}
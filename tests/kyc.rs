@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+use serde_json::{json, Value};
+use common::*;
+
+async fn submit(api: &Api, last_name: &str) -> (i32, Value) {
+    let customer_id = create_customer_with(api, json!({"lastName": last_name})).await;
+    let (status, _) = api.post(&format!("/api/customer/{}/kyc/documents", customer_id), json!({
+        "type": "passport", "fileName": "passport.jpg", "contentType": "image/jpeg", "size": 1024
+    })).await;
+    assert_eq!(status, 200);
+    let (status, kyc) = api.post(&format!("/api/customer/{}/kyc/submit", customer_id), json!({})).await;
+    assert_eq!(status, 200, "submit failed: {}", kyc);
+    (customer_id, kyc)
+}
+
+#[tokio::test]
+async fn pending_verifications_are_polled_until_decided() {
+    let server = Server::start_with(&[("KYC_POLL_INTERVAL", "1")]);
+    let merchant = create_merchant("Pending kyc").await;
+    let api = server.login(&merchant).await;
+
+    let (customer_id, kyc) = submit(&api, "Review").await;
+    assert_eq!(kyc["status"], "pending");
+    for _ in 0..50 {
+        let (_, kyc) = api.get(&format!("/api/customer/{}/kyc", customer_id)).await;
+        if kyc["status"] == "verified" {
+            return;
+        }
+        assert_eq!(kyc["status"], "pending");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("customer: {} is still pending", customer_id);
+}
+
+#[tokio::test]
+async fn provider_failures_can_be_submitted_again() {
+    let server = Server::start();
+    let merchant = create_merchant("Unavailable kyc").await;
+    let api = server.login(&merchant).await;
+
+    let (customer_id, kyc) = submit(&api, "Unavailable").await;
+    assert_eq!(kyc["status"], "unverified");
+    assert!(kyc["reason"].as_str().unwrap().contains("submit again"));
+    let (status, kyc) = api.post(&format!("/api/customer/{}/kyc/submit", customer_id), json!({})).await;
+    assert_eq!(status, 200, "resubmit failed: {}", kyc);
+}
+
+#[tokio::test]
+async fn identity_changes_reset_verification() {
+    let server = Server::start();
+    let merchant = create_merchant("Changed identity").await;
+    let api = server.login(&merchant).await;
+
+    let (customer_id, kyc) = submit(&api, "Doe").await;
+    assert_eq!(kyc["status"], "verified");
+    let (_, customer) = api.patch(&format!("/api/customer/{}", customer_id),
+                                  json!({"email": "jane.doe@example.com", "phone": "+14155550199"})).await;
+    assert_eq!(customer["kyc_status"], "verified");
+
+    for change in [json!({"lastName": "Smith"}), json!({"birthDate": "1991-02-02"}), json!({"address": "2 Main St"})] {
+        let (status, customer) = api.patch(&format!("/api/customer/{}", customer_id), change.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(customer["kyc_status"], "unverified", "{} kept the verification", change);
+        assert_eq!(customer["kyc_reason"], "identity details were changed");
+        let (_, kyc) = api.post(&format!("/api/customer/{}/kyc/submit", customer_id), json!({})).await;
+        assert_eq!(kyc["status"], "verified");
+    }
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn invalid_limits_are_reported_as_such() {
+    let server = Server::start();
+    let merchant = create_merchant("Invalid limits").await;
+    let api = server.login(&merchant).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let path = format!("/api/card/{}/limits", card_id);
+
+    for limits in [json!({"daily": 0}), json!({"weeklyCount": -1}), json!({"allowedFrom": "09:00:00"})] {
+        let (status, problem) = api.put(&path, limits.clone()).await;
+        assert_eq!(status, 400, "{} was saved", limits);
+        assert_eq!(problem["code"], "invalid_limit");
+    }
+    let (status, body) = api.put(&path, json!({"daily": 500})).await;
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[tokio::test]
+async fn captures_count_against_limits_after_their_hold_is_voided() {
+    let server = Server::start();
+    let merchant = create_merchant("Voided hold limits").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+    let (status, body) = api.put(&format!("/api/card/{}/limits", card_id), json!({"daily": 200, "dailyCount": 2})).await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (_, hold) = api.post("/api/card/authorize", spend(card_id, 80)).await;
+    let hold_id = hold["trans_id"].as_i64().unwrap();
+    let (status, body) = api.post(&format!("/api/card/authorization/{}/capture", hold_id),
+                                  json!({"amount": 50, "orderId": order_id("capture")})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = api.post(&format!("/api/card/authorization/{}/void", hold_id), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+
+    // the 50 captured still count, the 30 released don't
+    let (status, problem) = api.post("/api/card/withdraw", spend(card_id, 160)).await;
+    assert_eq!(status, 422, "{}", problem);
+    assert_eq!(problem["code"], "limit_exceeded");
+    // the hold and its capture are one spend, so there is room for one more
+    let (status, body) = api.post("/api/card/withdraw", spend(card_id, 50)).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, problem) = api.post("/api/card/withdraw", spend(card_id, 10)).await;
+    assert_eq!(status, 422, "{}", problem);
+    assert_eq!(problem["code"], "limit_exceeded");
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use serde_json::{json, Value};
+use hyper::Method;
+use common::*;
+
+// merchant B knows every id merchant A uses, none of them may be read or changed with B's token
+#[tokio::test]
+async fn merchants_cannot_reach_each_others_resources() {
+    let server = Server::start();
+    let owner = create_merchant("Owner").await;
+    let other = create_merchant("Other").await;
+    let a = server.login(&owner).await;
+    let b = server.login(&other).await;
+
+    fund(&a, owner.account_id, 1000).await;
+    let customer_id = create_customer(&a).await;
+    let card_id = create_card(&a, &owner, customer_id).await;
+    let spend_order = spend(card_id, 100);
+    let (_, spent) = a.post("/api/card/withdraw", spend_order.clone()).await;
+    let trans_id = spent["trans_id"].as_i64().unwrap();
+    let (_, authorized) = a.post("/api/card/authorize", spend(card_id, 50)).await;
+    let auth_id = authorized["trans_id"].as_i64().unwrap();
+
+    let requests: Vec<(Method, String, Option<Value>)> = vec![
+        (Method::GET, format!("/api/customer/{}", customer_id), None),
+        (Method::PATCH, format!("/api/customer/{}", customer_id), Some(json!({"firstName": "Mallory"}))),
+        (Method::POST, format!("/api/customer/{}/deactivate", customer_id), Some(json!({}))),
+        (Method::GET, format!("/api/customer/{}/kyc", customer_id), None),
+        (Method::POST, format!("/api/customer/{}/kyc/documents", customer_id), Some(json!({
+            "type": "passport", "fileName": "passport.jpg", "contentType": "image/jpeg", "size": 1024
+        }))),
+        (Method::POST, format!("/api/customer/{}/kyc/submit", customer_id), Some(json!({}))),
+        (Method::POST, "/api/card".to_string(), Some(json!({
+            "customerId": customer_id, "accountId": other.account_id, "programId": other.program_id
+        }))),
+        (Method::POST, "/api/card".to_string(), Some(json!({
+            "customerId": create_customer(&b).await, "accountId": owner.account_id, "programId": other.program_id
+        }))),
+        (Method::POST, "/api/card".to_string(), Some(json!({
+            "customerId": create_customer(&b).await, "accountId": other.account_id, "programId": owner.program_id
+        }))),
+        (Method::POST, "/api/card/deposit".to_string(), Some(json!({
+            "cardId": card_id, "amount": 100, "orderId": order_id("deposit")
+        }))),
+        (Method::POST, "/api/card/withdraw".to_string(), Some(spend(card_id, 100))),
+        (Method::POST, "/api/card/authorize".to_string(), Some(spend(card_id, 100))),
+        (Method::POST, format!("/api/card/authorization/{}/capture", auth_id), Some(json!({"orderId": order_id("capture")}))),
+        (Method::POST, format!("/api/card/authorization/{}/void", auth_id), Some(json!({}))),
+        (Method::POST, format!("/api/card/{}/freeze", card_id), Some(json!({}))),
+        (Method::POST, format!("/api/card/{}/unfreeze", card_id), Some(json!({}))),
+        (Method::POST, format!("/api/card/{}/terminate", card_id), Some(json!({}))),
+        (Method::PUT, format!("/api/card/{}/limits", card_id), Some(json!({"daily": 1}))),
+        (Method::PUT, format!("/api/card/{}/restrictions", card_id), Some(json!({"blockedMccs": ["5814"]}))),
+        (Method::POST, format!("/api/card/{}/reveal", card_id), Some(json!({}))),
+        (Method::GET, format!("/api/card/{}/balance", card_id), None),
+        (Method::GET, format!("/api/card/{}/transactions", card_id), None),
+        (Method::POST, "/api/account/fund".to_string(), Some(json!({
+            "accountId": owner.account_id, "amount": 100, "orderId": order_id("fund")
+        }))),
+        (Method::GET, format!("/api/account/{}/balance", owner.account_id), None),
+        (Method::GET, format!("/api/account/{}/transactions", owner.account_id), None),
+        (Method::GET, format!("/api/transaction/{}", trans_id), None),
+        (Method::GET, format!("/api/transaction?orderId={}", spend_order["orderId"].as_str().unwrap()), None),
+        (Method::POST, format!("/api/transaction/{}/reverse", trans_id), Some(json!({"orderId": order_id("reverse")}))),
+        (Method::POST, format!("/api/transaction/{}/refund", trans_id), Some(json!({
+            "amount": 10, "orderId": order_id("refund")
+        }))),
+    ];
+    for (method, path, body) in requests {
+        let (status, problem) = b.call(method.clone(), &path, body).await;
+        assert_eq!(status, 404, "{} {} was not rejected: {}", method, path, problem);
+    }
+
+    let (_, found) = b.get("/api/customers?email=jane@example.com").await;
+    assert!(found["customers"].as_array().unwrap().iter().all(|customer| customer["id"] != customer_id),
+            "search returned another merchant's customer: {}", found);
+
+    // nothing B tried left a mark on A's resources
+    let (_, customer) = a.get(&format!("/api/customer/{}", customer_id)).await;
+    assert_eq!(customer["first_name"], "Jane");
+    assert_eq!(customer["active"], true);
+    let (_, balance) = a.get(&format!("/api/account/{}/balance", owner.account_id)).await;
+    assert_eq!(balance["posted"], 900);
+    assert_eq!(balance["available"], 850);
+    let (status, _) = a.post("/api/card/withdraw", spend(card_id, 10)).await;
+    assert_eq!(status, 200, "card is no longer active");
+    let (_, transaction) = a.get(&format!("/api/transaction/{}", auth_id)).await;
+    assert_eq!(transaction["status"], "authorized");
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn merchant_order_ids_dont_collide_with_postings_the_api_makes() {
+    let server = Server::start();
+    let merchant = create_merchant("System postings").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 1000).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+
+    // the orderId the termination sweep used to be keyed by
+    let order_id = format!("card-{}-termination", card_id);
+    let (status, deposit) = api.post("/api/card/deposit", json!({
+        "cardId": card_id, "amount": 300, "orderId": order_id
+    })).await;
+    assert_eq!(status, 200, "{}", deposit);
+
+    let (status, body) = api.post(&format!("/api/card/{}/terminate", card_id), json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, balance) = api.get(&format!("/api/account/{}/balance", merchant.account_id)).await;
+    assert_eq!(balance["available"], 1000);
+
+    let (status, found) = api.get(&format!("/api/transaction?orderId={}", order_id)).await;
+    assert_eq!(status, 200, "{}", found);
+    assert_eq!(found["id"], deposit["trans_id"]);
+    assert_eq!(found["type"], "virtual_card_deposit");
+}
@@ -0,0 +1,18 @@
+mod common;
+
+use serde_json::json;
+use common::*;
+
+#[tokio::test]
+async fn unknown_merchants_fail_like_wrong_secrets() {
+    let server = Server::start();
+    let merchant = create_merchant("Token").await;
+    let api = server.anonymous();
+
+    let (status, wrong_secret) = api.post("/api/token", json!({"merchantId": merchant.id, "secret": "wrong"})).await;
+    assert_eq!(status, 401);
+    let (status, unknown) = api.post("/api/token", json!({"merchantId": i32::MAX, "secret": "wrong"})).await;
+    assert_eq!(status, 401);
+    assert_eq!(unknown, wrong_secret);
+    assert_eq!(unknown["code"], "invalid_credentials");
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use serde_json::Value;
+use common::*;
+
+fn statuses(transaction: &Value) -> Vec<&str> {
+    transaction["status_history"].as_array().unwrap().iter().map(|change| change["status"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn spends_are_pending_until_they_are_posted_or_fail() {
+    let server = Server::start();
+    let merchant = create_merchant("Transaction status").await;
+    let api = server.login(&merchant).await;
+    fund(&api, merchant.account_id, 100).await;
+    let card_id = create_card(&api, &merchant, create_customer(&api).await).await;
+
+    let (_, authorized) = api.post("/api/card/authorize", spend(card_id, 50)).await;
+    let (_, authorization) = api.get(&format!("/api/transaction/{}", authorized["trans_id"])).await;
+    assert_eq!(authorization["status"], "authorized");
+    assert_eq!(statuses(&authorization), vec!["pending", "authorized"]);
+
+    // a declined spend stays in the history as failed and its orderId can be tried again
+    let declined = spend(card_id, 300);
+    let (status, problem) = api.post("/api/card/withdraw", declined.clone()).await;
+    assert_eq!(status, 422, "{}", problem);
+    assert_eq!(problem["code"], "insufficient_funds");
+    let (status, history) = api.get(&format!("/api/card/{}/transactions?status=failed", card_id)).await;
+    assert_eq!(status, 200, "{}", history);
+    let failed = &history["transactions"][0];
+    assert_eq!(failed["order_id"], declined["orderId"]);
+    assert_eq!(failed["amount"], 0);
+    assert_eq!(statuses(failed), vec!["pending", "failed"]);
+
+    fund(&api, merchant.account_id, 500).await;
+    let (status, body) = api.post("/api/card/withdraw", declined.clone()).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, withdrawal) = api.get(&format!("/api/transaction?orderId={}", declined["orderId"].as_str().unwrap())).await;
+    assert_eq!(withdrawal["id"], body["trans_id"]);
+    assert_eq!(statuses(&withdrawal), vec!["pending", "completed"]);
+
+    // nothing is left pending once the spends are answered
+    let (status, history) = api.get(&format!("/api/card/{}/transactions?status=pending", card_id)).await;
+    assert_eq!(status, 200, "{}", history);
+    assert!(history["transactions"].as_array().unwrap().is_empty(), "{}", history);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use warp::Filter;
+use common::*;
+
+const DELIVERY_WAIT: Duration = Duration::from_secs(10);
+
+// an endpoint that records the events it gets and whether their delivery was locked while it was sent
+async fn start_stub() -> (String, Arc<Mutex<Vec<(i64, bool)>>>) {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let stub_calls = calls.clone();
+    let route = warp::post().and(warp::body::json()).and_then(move |event: Value| {
+        let calls = stub_calls.clone();
+        async move {
+            let event_id = event["id"].as_i64().unwrap();
+            let unlocked = db().await.query("select id from webhook_delivery where event_id = $1 for update nowait",
+                                            &[&(event_id as i32)]).await.is_ok();
+            calls.lock().unwrap().push((event_id, unlocked));
+            Ok::<_, Infallible>(warp::reply())
+        }
+    });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    (format!("http://{}/events", addr), calls)
+}
+
+async fn wait_for_delivery(api: &Api) -> Value {
+    let started = Instant::now();
+    loop {
+        let (_, events) = api.get("/api/webhook/events").await;
+        let delivery = events["events"][0]["deliveries"][0].clone();
+        if delivery["attempts"].as_i64().unwrap_or(0) > 0 || started.elapsed() > DELIVERY_WAIT {
+            return delivery;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn only_https_endpoints_on_public_hosts_are_registered() {
+    let server = Server::start();
+    let merchant = create_merchant("Webhook endpoints").await;
+    let api = server.login(&merchant).await;
+
+    for url in ["http://example.com/events", "https://localhost/events", "https://127.0.0.1/events",
+        "https://10.0.0.8/events", "https://169.254.169.254/latest", "https://[::1]/events", "https://intranet/events"] {
+        let (status, problem) = api.post("/api/webhook/endpoint", json!({"url": url})).await;
+        assert_eq!(status, 400, "{} was registered", url);
+        assert_eq!(problem["code"], "invalid_webhook_endpoint");
+    }
+    let (status, body) = api.post("/api/webhook/endpoint", json!({"url": "https://example.com/events"})).await;
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[tokio::test]
+async fn events_are_sent_without_holding_their_delivery() {
+    let server = Server::start_with(&[("WEBHOOK_ALLOW_LOCAL", "true"), ("WEBHOOK_INTERVAL", "1")]);
+    let merchant = create_merchant("Webhook delivery").await;
+    let api = server.login(&merchant).await;
+    let (url, calls) = start_stub().await;
+    let (status, body) = api.post("/api/webhook/endpoint", json!({"url": url})).await;
+    assert_eq!(status, 200, "{}", body);
+
+    create_customer(&api).await;
+    let delivery = wait_for_delivery(&api).await;
+    assert_eq!(delivery["status"], "delivered", "{}", delivery);
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 1);
+    assert!(calls[0].1, "the delivery was locked while the endpoint was called");
+}
+
+#[tokio::test]
+async fn local_endpoints_saved_earlier_are_not_called() {
+    let server = Server::start_with(&[("WEBHOOK_INTERVAL", "1")]);
+    let merchant = create_merchant("Webhook local endpoint").await;
+    let api = server.login(&merchant).await;
+    let (url, calls) = start_stub().await;
+    db().await.execute("insert into webhook_endpoint (merch_id, url, secret, active, created) \
+     values ($1, $2, 'secret', true, now())", &[&merchant.id, &url]).await.unwrap();
+
+    create_customer(&api).await;
+    let delivery = wait_for_delivery(&api).await;
+    assert_eq!(delivery["status"], "pending", "{}", delivery);
+    assert_eq!(delivery["last_error"], "url must be an absolute https url");
+    assert!(calls.lock().unwrap().is_empty());
+}